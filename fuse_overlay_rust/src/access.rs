@@ -0,0 +1,145 @@
+use fuser::{FileAttr, FileType};
+use std::cell::OnceCell;
+use std::fs;
+
+/// The process behind a request, as far as permission checks go.
+pub struct Caller {
+    pub uid: u32,
+    pub gid: u32,
+    pid: u32,
+    // Supplementary groups, read on first use
+    groups: OnceCell<Vec<u32>>,
+}
+
+impl Caller {
+    pub fn new(uid: u32, gid: u32, pid: u32) -> Self {
+        Self { uid, gid, pid, groups: OnceCell::new() }
+    }
+
+    /// Whether `gid` is the caller's primary group or one of its supplementary
+    /// groups. Those come from /proc, so a caller that has already exited only
+    /// has its primary group.
+    pub fn in_group(&self, gid: u32) -> bool {
+        gid == self.gid || self.groups.get_or_init(|| supplementary_groups(self.pid)).contains(&gid)
+    }
+}
+
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+    let Ok(status) = fs::read_to_string(format!("/proc/{}/status", pid)) else { return Vec::new() };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))
+        .map(|groups| groups.split_whitespace().filter_map(|g| g.parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Whether `caller` may access a file with `attr` for `mask` (any of R_OK,
+/// W_OK, X_OK). Mounting with `default_permissions` leaves the checks to the
+/// kernel instead.
+pub fn allowed(attr: &FileAttr, caller: &Caller, mask: i32) -> bool {
+    if mask == libc::F_OK {
+        return true;
+    }
+    let mode = attr.perm as i32;
+
+    // root may execute only what is executable for someone
+    if caller.uid == 0 {
+        return mask & libc::X_OK == 0 || attr.kind == FileType::Directory || mode & 0o111 != 0;
+    }
+
+    let granted = if caller.uid == attr.uid {
+        mode >> 6
+    } else if caller.in_group(attr.gid) {
+        mode >> 3
+    } else {
+        mode
+    } & 0o7;
+    mask & granted == mask
+}
+
+/// Removing or renaming `child` out of `dir`: the sticky bit limits that to the
+/// owner of either, on top of write and search permission on the directory.
+pub fn may_remove(dir: &FileAttr, child: Option<&FileAttr>, caller: &Caller) -> bool {
+    if !allowed(dir, caller, libc::W_OK | libc::X_OK) {
+        return false;
+    }
+    match child {
+        Some(child) if dir.perm & libc::S_ISVTX as u16 != 0 => {
+            caller.uid == 0 || caller.uid == dir.uid || caller.uid == child.uid
+        }
+        _ => true,
+    }
+}
+
+/// Open flags to the access they need. With the writeback cache the kernel
+/// turns O_WRONLY into O_RDWR to fill partial pages, so an O_RDWR open then
+/// only needs write access.
+pub fn open_mask(flags: i32, writeback: bool) -> i32 {
+    let mask = match flags & libc::O_ACCMODE {
+        libc::O_RDONLY => libc::R_OK,
+        libc::O_WRONLY => libc::W_OK,
+        _ if writeback => libc::W_OK,
+        _ => libc::R_OK | libc::W_OK,
+    };
+    if flags & libc::O_TRUNC != 0 {
+        mask | libc::W_OK
+    } else {
+        mask
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn attr(perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            ino: 2,
+            size: 0,
+            blocks: 0,
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::RegularFile,
+            perm,
+            nlink: 1,
+            uid,
+            gid,
+            rdev: 0,
+            flags: 0,
+            blksize: 512,
+        }
+    }
+
+    #[test]
+    fn group_permissions_cover_supplementary_groups() {
+        let status = fs::read_to_string("/proc/self/status").unwrap();
+        let groups: Vec<u32> = status
+            .lines()
+            .find_map(|l| l.strip_prefix("Groups:"))
+            .map(|g| g.split_whitespace().filter_map(|g| g.parse().ok()).collect())
+            .unwrap_or_default();
+        let caller = Caller::new(1000, 54321, std::process::id());
+        assert!(caller.in_group(54321));
+        for gid in &groups {
+            assert!(caller.in_group(*gid));
+            assert!(allowed(&attr(0o060, 0, *gid), &caller, libc::R_OK | libc::W_OK));
+        }
+
+        let gone = Caller::new(1000, 54321, u32::MAX);
+        assert!(!gone.in_group(54322));
+        assert!(!allowed(&attr(0o060, 0, 54322), &gone, libc::R_OK));
+    }
+
+    #[test]
+    fn writeback_opens_only_need_write_access() {
+        assert_eq!(open_mask(libc::O_RDONLY, false), libc::R_OK);
+        assert_eq!(open_mask(libc::O_RDWR, false), libc::R_OK | libc::W_OK);
+        // The kernel widened an O_WRONLY open of a mode-0200 file
+        assert_eq!(open_mask(libc::O_RDWR, true), libc::W_OK);
+        assert!(allowed(&attr(0o200, 1000, 1000), &Caller::new(1000, 1000, 0), open_mask(libc::O_RDWR, true)));
+        assert_eq!(open_mask(libc::O_RDONLY | libc::O_TRUNC, true), libc::R_OK | libc::W_OK);
+    }
+}
@@ -1,126 +1,189 @@
 use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
-/// LRU cache for file contents with size limits
+/// LRU cache for file contents with size limits. Prefetched entries are
+/// counted against their own byte budget until a read uses them, so
+/// speculative loads cannot push out content that was actually read.
+/// Content is shared with readers rather than copied out.
 pub struct LruCache {
     data: Mutex<LruCacheInner>,
 }
 
+/// Which prefetch strategy loaded an entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Prefetched {
+    // Directory listings, profiles, warm-up
+    Speculative,
+    // Expected from earlier access traces
+    Predicted,
+    // Changed in recent commits
+    History,
+}
+
+struct CacheEntry {
+    data: Arc<Vec<u8>>,
+    // Loaded ahead of a read and not read since
+    prefetched: Option<Prefetched>,
+}
+
 struct LruCacheInner {
-    cache: HashMap<PathBuf, Vec<u8>>,
+    cache: HashMap<PathBuf, CacheEntry>,
     access_order: VecDeque<PathBuf>,
     current_size: usize,
-    max_size: usize,      // Maximum total bytes
-    max_entries: usize,   // Maximum number of entries
+    prefetch_size: usize,
+    max_size: usize,          // Maximum bytes read on demand
+    max_prefetch_size: usize, // Maximum bytes prefetched and not read yet
+    max_entries: usize,       // Maximum number of entries
+    max_entry_size: usize,    // Larger content is not prefetched
+}
+
+impl LruCacheInner {
+    fn insert(&mut self, path: PathBuf, data: Vec<u8>, source: Option<Prefetched>) -> bool {
+        let prefetched = source.is_some();
+        let data_size = data.len();
+        // Demand reads are cached whatever their size, or every chunk of a
+        // large file would inflate the blob again
+        if data_size > self.budget(prefetched) || (prefetched && data_size > self.max_entry_size) {
+            return false;
+        }
+
+        // Remove old entry if exists
+        self.take(&path);
+
+        // Evict until we have space; prefetching only ever makes room among prefetched entries
+        let kind = if prefetched { Some(true) } else { None };
+        while self.cache.len() >= self.max_entries && self.evict(kind) {}
+        if self.cache.len() >= self.max_entries {
+            return false;
+        }
+        while self.used(prefetched) + data_size > self.budget(prefetched) && self.evict(Some(prefetched)) {}
+
+        // Insert new entry
+        self.cache.insert(path.clone(), CacheEntry { data: Arc::new(data), prefetched: source });
+        self.access_order.push_front(path);
+        *self.used_mut(prefetched) += data_size;
+        true
+    }
+
+    fn take(&mut self, path: &PathBuf) -> Option<CacheEntry> {
+        let entry = self.cache.remove(path)?;
+        *self.used_mut(entry.prefetched.is_some()) -= entry.data.len();
+        if let Some(pos) = self.access_order.iter().position(|p| p == path) {
+            self.access_order.remove(pos);
+        }
+        Some(entry)
+    }
+
+    // Drop the least recently used entry, of one kind if given
+    fn evict(&mut self, prefetched: Option<bool>) -> bool {
+        let victim = self.access_order.iter().rposition(|p| {
+            prefetched.is_none_or(|kind| self.cache.get(p).is_some_and(|e| e.prefetched.is_some() == kind))
+        });
+        match victim.and_then(|pos| self.access_order.get(pos).cloned()) {
+            Some(path) => self.take(&path).is_some(),
+            None => false,
+        }
+    }
+
+    fn budget(&self, prefetched: bool) -> usize {
+        if prefetched { self.max_prefetch_size } else { self.max_size }
+    }
+
+    fn used(&self, prefetched: bool) -> usize {
+        if prefetched { self.prefetch_size } else { self.current_size }
+    }
+
+    fn used_mut(&mut self, prefetched: bool) -> &mut usize {
+        if prefetched { &mut self.prefetch_size } else { &mut self.current_size }
+    }
 }
 
 impl LruCache {
-    pub fn new(max_size: usize, max_entries: usize) -> Self {
+    pub fn new(max_size: usize, max_prefetch_size: usize, max_entries: usize, max_entry_size: usize) -> Self {
         Self {
             data: Mutex::new(LruCacheInner {
                 cache: HashMap::new(),
                 access_order: VecDeque::new(),
                 current_size: 0,
+                prefetch_size: 0,
                 max_size,
+                max_prefetch_size,
                 max_entries,
+                max_entry_size,
             }),
         }
     }
 
-    pub fn get(&self, path: &PathBuf) -> Option<Vec<u8>> {
+    /// Cached content, and on the first read of a prefetched entry, what prefetched it.
+    pub fn get(&self, path: &PathBuf) -> Option<(Arc<Vec<u8>>, Option<Prefetched>)> {
         let mut inner = self.data.lock().unwrap();
-        
-        // Clone data first to avoid borrow checker issues
-        let result = inner.cache.get(path).cloned();
-        
-        if result.is_some() {
-            // Move to front (most recently used)
-            if let Some(pos) = inner.access_order.iter().position(|p| p == path) {
-                inner.access_order.remove(pos);
-            }
-            inner.access_order.push_front(path.clone());
+
+        let entry = inner.cache.get_mut(path)?;
+        let result = entry.data.clone();
+        // First read of a prefetched entry: it now counts as read on demand
+        let promoted = entry.prefetched.take();
+
+        // Move to front (most recently used)
+        if let Some(pos) = inner.access_order.iter().position(|p| p == path) {
+            inner.access_order.remove(pos);
+        }
+        inner.access_order.push_front(path.clone());
+
+        if promoted.is_some() {
+            inner.prefetch_size -= result.len();
+            inner.current_size += result.len();
+            while inner.current_size > inner.max_size && inner.evict(Some(false)) {}
         }
-        
-        result
+
+        Some((result, promoted))
     }
 
-    pub fn insert(&self, path: PathBuf, data: Vec<u8>) {
+    /// Cache content read on demand; false when it is too large to keep.
+    pub fn insert(&self, path: PathBuf, data: Vec<u8>) -> bool {
         let mut inner = self.data.lock().unwrap();
-        let data_size = data.len();
-        
-        // Remove old entry if exists
-        if let Some(old_data) = inner.cache.remove(&path) {
-            inner.current_size -= old_data.len();
-            if let Some(pos) = inner.access_order.iter().position(|p| p == &path) {
-                inner.access_order.remove(pos);
-            }
-        }
-        
-        // Evict until we have space
-        while (inner.current_size + data_size > inner.max_size 
-               || inner.cache.len() >= inner.max_entries)
-               && !inner.cache.is_empty() {
-            if let Some(old_path) = inner.access_order.pop_back() {
-                if let Some(old_data) = inner.cache.remove(&old_path) {
-                    inner.current_size -= old_data.len();
-                }
-            }
-        }
-        
-        // Insert new entry
-        inner.cache.insert(path.clone(), data);
-        inner.access_order.push_front(path);
-        inner.current_size += data_size;
+        inner.insert(path, data, None)
     }
 
-    pub fn remove(&self, path: &PathBuf) -> Option<Vec<u8>> {
+    /// Cache prefetched content, only if nothing is cached for `path` yet. Check
+    /// and insert happen under one lock, so background loads never replace
+    /// content cached meanwhile.
+    pub fn insert_prefetched(&self, path: PathBuf, data: Vec<u8>, source: Prefetched) -> bool {
         let mut inner = self.data.lock().unwrap();
-        
-        if let Some(data) = inner.cache.remove(path) {
-            inner.current_size -= data.len();
-            if let Some(pos) = inner.access_order.iter().position(|p| p == path) {
-                inner.access_order.remove(pos);
-            }
-            Some(data)
-        } else {
-            None
+        if inner.cache.contains_key(&path) {
+            return false;
         }
+        inner.insert(path, data, Some(source))
+    }
+
+    pub fn remove(&self, path: &PathBuf) -> Option<Arc<Vec<u8>>> {
+        let mut inner = self.data.lock().unwrap();
+        inner.take(path).map(|e| e.data)
     }
 
     pub fn contains_key(&self, path: &PathBuf) -> bool {
         self.data.lock().unwrap().cache.contains_key(path)
     }
 
-    #[allow(dead_code)]
     pub fn clear(&self) {
         let mut inner = self.data.lock().unwrap();
         inner.cache.clear();
         inner.access_order.clear();
         inner.current_size = 0;
+        inner.prefetch_size = 0;
     }
 
-    #[allow(dead_code)]
     pub fn stats(&self) -> CacheStats {
         let inner = self.data.lock().unwrap();
         CacheStats {
             entries: inner.cache.len(),
-            total_bytes: inner.current_size,
+            total_bytes: inner.current_size + inner.prefetch_size,
+            prefetch_bytes: inner.prefetch_size,
             max_bytes: inner.max_size,
+            max_prefetch_bytes: inner.max_prefetch_size,
             max_entries: inner.max_entries,
         }
     }
-
-    /// Iterate over all entries (for operations like readdir)
-    pub fn iter<F>(&self, mut f: F) 
-    where
-        F: FnMut(&PathBuf, &Vec<u8>),
-    {
-        let inner = self.data.lock().unwrap();
-        for (path, data) in inner.cache.iter() {
-            f(path, data);
-        }
-    }
 }
 
 #[allow(dead_code)]
@@ -128,7 +191,9 @@ impl LruCache {
 pub struct CacheStats {
     pub entries: usize,
     pub total_bytes: usize,
+    pub prefetch_bytes: usize,
     pub max_bytes: usize,
+    pub max_prefetch_bytes: usize,
     pub max_entries: usize,
 }
 
@@ -138,3 +203,55 @@ impl CacheStats {
         (self.total_bytes as f64 / self.max_bytes as f64) * 100.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefetch_has_its_own_budget() {
+        let cache = LruCache::new(8, 4, 100, 8);
+        cache.insert(PathBuf::from("a"), vec![0; 4]);
+        cache.insert(PathBuf::from("b"), vec![0; 4]);
+        assert!(cache.insert_prefetched(PathBuf::from("p1"), vec![0; 3], Prefetched::Speculative));
+        assert!(cache.insert_prefetched(PathBuf::from("p2"), vec![0; 3], Prefetched::History));
+        assert!(!cache.insert_prefetched(PathBuf::from("huge"), vec![0; 5], Prefetched::Speculative));
+
+        // Prefetching pushed out the older prefetch, not demand reads
+        assert!(cache.contains_key(&PathBuf::from("a")));
+        assert!(cache.contains_key(&PathBuf::from("b")));
+        assert!(!cache.contains_key(&PathBuf::from("p1")));
+
+        // A read moves the entry into the demand budget, evicting the oldest demand read
+        assert_eq!(cache.get(&PathBuf::from("p2")).unwrap().1, Some(Prefetched::History));
+        assert_eq!(cache.get(&PathBuf::from("p2")).unwrap().1, None);
+        assert!(!cache.contains_key(&PathBuf::from("a")));
+        let stats = cache.stats();
+        assert_eq!(stats.prefetch_bytes, 0);
+        assert_eq!(stats.total_bytes, 7);
+    }
+
+    #[test]
+    fn prefetching_at_the_entry_limit_only_replaces_prefetched_entries() {
+        let cache = LruCache::new(100, 100, 2, 10);
+        cache.insert(PathBuf::from("a"), vec![0; 1]);
+        cache.insert(PathBuf::from("b"), vec![0; 1]);
+        assert!(!cache.insert_prefetched(PathBuf::from("p1"), vec![0; 1], Prefetched::Speculative));
+        assert!(cache.contains_key(&PathBuf::from("a")));
+        assert!(cache.contains_key(&PathBuf::from("b")));
+
+        // Demand reads may push out anything; a prefetch then replaces the older prefetch
+        cache.remove(&PathBuf::from("b"));
+        assert!(cache.insert_prefetched(PathBuf::from("p1"), vec![0; 1], Prefetched::Speculative));
+        assert!(cache.insert_prefetched(PathBuf::from("p2"), vec![0; 1], Prefetched::Speculative));
+        assert!(cache.contains_key(&PathBuf::from("a")));
+        assert!(!cache.contains_key(&PathBuf::from("p1")));
+        cache.insert(PathBuf::from("c"), vec![0; 1]);
+        assert!(!cache.contains_key(&PathBuf::from("a")));
+        assert_eq!(cache.stats().entries, 2);
+
+        // Content over the per-entry limit is only cached once read
+        assert!(!cache.insert_prefetched(PathBuf::from("big"), vec![0; 11], Prefetched::Speculative));
+        assert!(cache.insert(PathBuf::from("big"), vec![0; 11]));
+    }
+}
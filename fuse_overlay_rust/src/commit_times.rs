@@ -0,0 +1,165 @@
+use dashmap::{DashMap, DashSet};
+use git2::{Repository, Sort};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::metrics::debug;
+
+// Upper bound on commits walked when looking for the last change to a path
+const MAX_HISTORY_WALK: usize = 10_000;
+
+/// Stable timestamps for files that come from git.
+pub struct CommitTimes {
+    head_time: SystemTime,
+    path_times: Arc<DashMap<PathBuf, SystemTime>>,
+    // Paths already handed to the history walker
+    queued: DashSet<PathBuf>,
+    // None with per-path times off
+    walker: Option<Sender<PathBuf>>,
+}
+
+impl CommitTimes {
+    /// With `resolved`, per-path times are on: history is walked on a thread of
+    /// its own, and each path is sent to `resolved` once its time is known. The
+    /// walk stops when this value is dropped, e.g. replaced on a HEAD switch.
+    pub fn new(repo: &Repository, head: git2::Oid, resolved: Option<Sender<PathBuf>>) -> Result<Self, git2::Error> {
+        let head_time = git_time_to_system(repo.find_commit(head)?.time());
+        let path_times = Arc::new(DashMap::new());
+        let walker = match resolved {
+            Some(resolved) => {
+                let walk_repo = Repository::open(repo.path())?;
+                let times = Arc::downgrade(&path_times);
+                let (tx, rx) = mpsc::channel::<PathBuf>();
+                thread::spawn(move || walk(&walk_repo, head, head_time, rx, times, resolved));
+                Some(tx)
+            }
+            None => None,
+        };
+        Ok(Self {
+            head_time,
+            path_times,
+            queued: DashSet::new(),
+            walker,
+        })
+    }
+
+    pub fn head_time(&self) -> SystemTime {
+        self.head_time
+    }
+
+    /// Time of `head`, or with per-path times enabled the time of the last
+    /// commit that changed `path`. That one is looked up in the background on
+    /// first use; the time of `head` stands in until it is known.
+    pub fn time_for(&self, path: &Path) -> SystemTime {
+        let Some(walker) = &self.walker else { return self.head_time; };
+        if path.as_os_str().is_empty() {
+            return self.head_time;
+        }
+        if let Some(t) = self.path_times.get(path) {
+            return *t;
+        }
+
+        if self.queued.insert(path.to_path_buf()) {
+            let _ = walker.send(path.to_path_buf());
+        }
+        self.head_time
+    }
+
+    /// Whether `time_for(path)` is only standing in until the walk reaches it.
+    pub fn pending(&self, path: &Path) -> bool {
+        self.walker.is_some() && !path.as_os_str().is_empty() && !self.path_times.contains_key(path)
+    }
+}
+
+fn walk(
+    repo: &Repository,
+    head: git2::Oid,
+    head_time: SystemTime,
+    paths: mpsc::Receiver<PathBuf>,
+    times: Weak<DashMap<PathBuf, SystemTime>>,
+    resolved: Sender<PathBuf>,
+) {
+    for path in paths {
+        let Some(times) = times.upgrade() else { return; };
+        let time = last_change_time(repo, head, &path).unwrap_or(head_time);
+        times.insert(path.clone(), time);
+        let _ = resolved.send(path);
+    }
+}
+
+fn git_time_to_system(time: git2::Time) -> SystemTime {
+    let secs = time.seconds();
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+    }
+}
+
+// Follows first parents from `head` until the entry at `path` differs from the
+// parent's. If the walk limit is hit, the oldest commit seen with the same entry is used.
+fn last_change_time(repo: &Repository, head: git2::Oid, path: &Path) -> Option<SystemTime> {
+    let mut walk = repo.revwalk().ok()?;
+    walk.push(head).ok()?;
+    walk.simplify_first_parent().ok()?;
+    walk.set_sorting(Sort::TOPOLOGICAL).ok()?;
+
+    let entry_id = |commit: &git2::Commit<'_>| {
+        commit.tree().ok()
+            .and_then(|t| t.get_path(path).ok())
+            .map(|e| e.id())
+    };
+
+    let mut last_seen = None;
+    for oid in walk.take(MAX_HISTORY_WALK) {
+        let oid = oid.ok()?;
+        let commit = repo.find_commit(oid).ok()?;
+        let current = entry_id(&commit);
+        let parent = commit.parent(0).ok().and_then(|p| entry_id(&p));
+        last_seen = Some(git_time_to_system(commit.time()));
+        if current != parent {
+            debug!("[TIMES] {:?} last changed in {}", path, oid);
+            return last_seen;
+        }
+    }
+
+    last_seen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{FileMode, Signature, Time};
+
+    #[test]
+    fn per_path_times_stand_in_until_the_walk_reports_them() {
+        let dir = std::env::temp_dir().join(format!("gitfs-times-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let commit = |secs: i64, files: &[(&str, &[u8])], parents: &[&git2::Commit<'_>]| {
+            let mut builder = repo.treebuilder(None).unwrap();
+            for (name, content) in files {
+                builder.insert(name, repo.blob(content).unwrap(), FileMode::Blob.into()).unwrap();
+            }
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let sig = Signature::new("test", "test@example.com", &Time::new(secs, 0)).unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "commit", &tree, parents).unwrap()
+        };
+        let first = commit(1_000, &[("old", b"a"), ("new", b"b")], &[]);
+        let first = repo.find_commit(first).unwrap();
+        let head = commit(2_000, &[("old", b"a"), ("new", b"c")], &[&first]);
+
+        let (tx, rx) = mpsc::channel();
+        let times = CommitTimes::new(&repo, head, Some(tx)).unwrap();
+        let old = Path::new("old");
+        assert_eq!(times.time_for(old), times.head_time());
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)).unwrap(), old);
+        assert!(!times.pending(old));
+        assert_eq!(times.time_for(old), UNIX_EPOCH + Duration::from_secs(1_000));
+        assert!(!times.pending(Path::new("")));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
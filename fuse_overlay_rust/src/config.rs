@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+const DEFAULT_HEAD_POLL_MS: u64 = 1000;
+const DEFAULT_PREFETCH_WORKERS: usize = 4;
+const DEFAULT_PREFETCH_MAX_BLOB_KB: u64 = 8 * 1024;
+const DEFAULT_PREFETCH_CACHE_MB: u64 = 512;
+const DEFAULT_PREFETCH_BACKOFF_MS: u64 = 20;
+const DEFAULT_PREFETCH_MAX_QUEUE: usize = 10_000;
+
+/// Runtime options, read from `GITFS_*` environment variables like `GITFS_DEBUG`.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    // Report the time of the last commit touching each path instead of HEAD's time
+    pub path_mtime: bool,
+    // Mount with `default_permissions` so the kernel checks access instead of the filesystem
+    pub default_permissions: bool,
+    // How often to check whether HEAD moved; 0 pins the mount to the commit it started on
+    pub head_poll_ms: u64,
+    // Size of the prefetch worker pool
+    pub prefetch_workers: usize,
+    // Record file opens to a trace in .git and prefetch what followed them on earlier runs
+    pub access_trace: bool,
+    // Prefetch profile (gitignore syntax); defaults to .git/fuse_prefetch_profile
+    pub prefetch_profile: Option<PathBuf>,
+    // Subdirectory levels a directory prefetch descends; 0 loads only the directory's own files
+    pub prefetch_depth: u32,
+    // Commits back from HEAD whose changed files are prefetched at mount; 0 disables it
+    pub prefetch_history: usize,
+    // Blobs larger than this are not prefetched; reads still cache them
+    pub prefetch_max_blob: u64,
+    // Background prefetch pauses while foreground requests average more than this; 0 never pauses
+    pub prefetch_backoff_ms: u64,
+    // Queued prefetch jobs past which speculative requests wait until the queue
+    // drains and the filesystem is idle, and waiting requests past which the
+    // oldest are dropped; 0 for no limit
+    pub prefetch_max_queue: usize,
+    // Cache bytes prefetched content may hold until it is read; separate from the demand-read budget
+    pub prefetch_cache_bytes: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            path_mtime: env_flag("GITFS_PATH_MTIME"),
+            default_permissions: env_flag("GITFS_DEFAULT_PERMISSIONS"),
+            head_poll_ms: env_u64("GITFS_HEAD_POLL_MS").unwrap_or(DEFAULT_HEAD_POLL_MS),
+            access_trace: env_flag("GITFS_ACCESS_TRACE"),
+            prefetch_profile: std::env::var_os("GITFS_PREFETCH_PROFILE").map(PathBuf::from),
+            prefetch_workers: env_u64("GITFS_PREFETCH_WORKERS").map_or(DEFAULT_PREFETCH_WORKERS, |n| n as usize),
+            prefetch_depth: env_u64("GITFS_PREFETCH_DEPTH").map_or(0, |n| n as u32),
+            prefetch_history: env_u64("GITFS_PREFETCH_HISTORY").map_or(0, |n| n as usize),
+            prefetch_max_blob: env_u64("GITFS_PREFETCH_MAX_BLOB_KB").unwrap_or(DEFAULT_PREFETCH_MAX_BLOB_KB) * 1024,
+            prefetch_backoff_ms: env_u64("GITFS_PREFETCH_BACKOFF_MS").unwrap_or(DEFAULT_PREFETCH_BACKOFF_MS),
+            prefetch_max_queue: env_u64("GITFS_PREFETCH_MAX_QUEUE").map_or(DEFAULT_PREFETCH_MAX_QUEUE, |n| n as usize),
+            prefetch_cache_bytes: (env_u64("GITFS_PREFETCH_CACHE_MB").unwrap_or(DEFAULT_PREFETCH_CACHE_MB) * 1024 * 1024) as usize,
+        }
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok()?.parse().ok()
+}
+
+fn env_flag(name: &str) -> bool {
+    matches!(
+        std::env::var(name).as_deref(),
+        Ok("1") | Ok("true") | Ok("yes") | Ok("on")
+    )
+}
@@ -0,0 +1,275 @@
+use anyhow::{bail, Context, Result};
+use git2::{ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+use std::collections::BTreeSet;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::cache::LruCache;
+use crate::invalidate::Invalidator;
+use crate::metrics::debug;
+use crate::node_cache::NodeCache;
+use crate::overlay_store::try_lock;
+use crate::prefetch::{Prefetcher, Priority};
+use crate::profile::Profile;
+
+/// Unix socket in the git directory through which `git_fuse_overlay warmup`
+/// reaches the running mount.
+pub const CONTROL_SOCKET: &str = "fuse_control";
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+const LOCK_SUFFIX: &str = ".lock";
+
+/// The mount's side of the control socket.
+pub struct Control {
+    pub repo_path: PathBuf,
+    pub prefetcher: Arc<Prefetcher>,
+    pub overlay: Arc<LruCache>,
+    // The commit the mount serves, and the one it switches to on its next request
+    pub head: Arc<Mutex<Oid>>,
+    pub pending_head: Arc<Mutex<Option<Oid>>>,
+    // For `invalidate`, after the overlay was changed from outside the mount
+    pub node_cache: Arc<NodeCache>,
+    pub invalidator: Invalidator,
+}
+
+/// The socket a mount listens on; removed when dropped. Another mount of the
+/// same repository gets AddrInUse instead of taking it over.
+pub struct ControlSocket {
+    path: PathBuf,
+    // Held for the life of the mount
+    _lock: File,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Control {
+    /// Answer requests on `socket_path` in the background.
+    pub fn serve(self, socket_path: &Path) -> io::Result<ControlSocket> {
+        let mut lock_path = socket_path.as_os_str().to_owned();
+        lock_path.push(LOCK_SUFFIX);
+        let Some(lock) = try_lock(Path::new(&lock_path))? else {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another mount owns the control socket"));
+        };
+        // A mount from before the lock file existed may still be listening
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, "another mount is listening"));
+        }
+        // Left over from a mount that did not shut down cleanly
+        let _ = fs::remove_file(socket_path);
+        let listener = UnixListener::bind(socket_path)?;
+        let socket = ControlSocket { path: socket_path.to_path_buf(), _lock: lock };
+        let control = Arc::new(self);
+        thread::spawn(move || {
+            for conn in listener.incoming().flatten() {
+                let control = control.clone();
+                thread::spawn(move || {
+                    if let Err(e) = control.handle(conn) {
+                        debug!("[CONTROL] request failed: {}", e);
+                    }
+                });
+            }
+        });
+        Ok(socket)
+    }
+
+    // A header line, then path lines until the client shuts down its side
+    fn handle(&self, conn: UnixStream) -> Result<()> {
+        let mut reader = BufReader::new(conn.try_clone()?);
+        let mut out = conn;
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let request = match header.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["warmup", ms] => ms.parse().ok().map(|ms| (Some(ms), false)),
+            ["warmup", ms, "all"] => ms.parse().ok().map(|ms| (Some(ms), true)),
+            ["invalidate"] => Some((None, false)),
+            _ => None,
+        };
+        let Some((timeout, all)) = request else {
+            writeln!(out, "error unknown request {:?}", header.trim())?;
+            return Ok(());
+        };
+        let lines: Vec<String> = reader.lines().collect::<io::Result<_>>()?;
+        match timeout {
+            Some(timeout) => self.warmup(out, &lines, Duration::from_millis(timeout), all),
+            None => self.invalidate(out, &lines),
+        }
+    }
+
+    // Request: `warmup <timeout ms> [all]` with path and glob lines; `all` loads
+    // files prefetching would skip. Reply: `progress <done> <total>` lines, then
+    // `done|timeout <done> <total> <cached>`.
+    fn warmup(&self, mut out: UnixStream, lines: &[String], timeout: Duration, all: bool) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+
+        let head = self.pending_head.lock().unwrap().unwrap_or(*self.head.lock().unwrap());
+        let repo = Repository::open(&self.repo_path)?;
+        let paths = expand(&repo, head, lines);
+        debug!("[CONTROL] warming {} files", paths.len());
+
+        let batch = self.prefetcher.warm_files(paths.clone(), head, Priority::High, all);
+        let finished = batch.wait(deadline, PROGRESS_INTERVAL, |done, total| {
+            let _ = writeln!(out, "progress {} {}", done, total);
+        });
+        let cached = paths.iter().filter(|p| self.overlay.contains_key(p)).count();
+        let status = if finished { "done" } else { "timeout" };
+        writeln!(out, "{} {} {} {}", status, batch.done(), paths.len(), cached)?;
+        Ok(())
+    }
+
+    // Request: `invalidate` with one path per line, changed in the overlay by
+    // something other than the mount. Reply: `done <paths the kernel held>`.
+    fn invalidate(&self, mut out: UnixStream, lines: &[String]) -> Result<()> {
+        let mut held = 0;
+        for line in lines.iter().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            let path = Path::new(line.strip_prefix("./").unwrap_or(line));
+            if self.invalidator.path(&self.node_cache, path) {
+                held += 1;
+            }
+        }
+        debug!("[CONTROL] invalidated {} of {} paths", held, lines.len());
+        writeln!(out, "done {}", held)?;
+        Ok(())
+    }
+}
+
+// Paths relative to the repository root, or gitignore-style globs matched
+// against `head`. Make dependency lines (`out.o: a.c b.h \`) work as they are.
+fn expand(repo: &Repository, head: Oid, lines: &[String]) -> Vec<PathBuf> {
+    let mut paths = BTreeSet::new();
+    let mut globs = String::new();
+    for line in lines.iter().filter(|l| !l.trim_start().starts_with('#')) {
+        for token in line.split_whitespace() {
+            if token == "\\" || token.ends_with(':') {
+                continue;
+            }
+            let token = token.strip_prefix("./").unwrap_or(token);
+            if token.contains(['*', '?', '[']) {
+                globs.push_str(token);
+                globs.push('\n');
+            } else {
+                paths.insert(PathBuf::from(token));
+            }
+        }
+    }
+
+    if !globs.is_empty() {
+        let profile = Profile::parse(&globs);
+        if let Ok(tree) = repo.find_commit(head).and_then(|c| c.tree()) {
+            let _ = tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+                if entry.kind() == Some(ObjectType::Blob)
+                    && let Some(name) = entry.name()
+                {
+                    let path = Path::new(dir).join(name);
+                    if profile.includes(&path) {
+                        paths.insert(path);
+                    }
+                }
+                TreeWalkResult::Ok
+            });
+        }
+    }
+    paths.into_iter().collect()
+}
+
+/// Client side of `warmup`: send `list` to the mount and print its progress.
+/// Absolute paths under `root` (the mountpoint) are made relative to it; `all`
+/// also loads files too large for, or excluded from, prefetching. False when
+/// the mount gave up at the timeout.
+pub fn warmup(socket_path: &Path, list: &str, root: Option<&Path>, timeout: Duration, all: bool) -> Result<bool> {
+    let mut conn = UnixStream::connect(socket_path)
+        .with_context(|| format!("no mount is listening on {:?}", socket_path))?;
+    // The mount reports at least every PROGRESS_INTERVAL, so silence means it is stuck
+    conn.set_read_timeout(Some(timeout + 10 * PROGRESS_INTERVAL))?;
+
+    writeln!(conn, "warmup {}{}", timeout.as_millis(), if all { " all" } else { "" })?;
+    for line in list.lines() {
+        let tokens: Vec<&str> = line
+            .split_whitespace()
+            .map(|t| match root.and_then(|r| Path::new(t).strip_prefix(r).ok()) {
+                Some(rel) => rel.to_str().unwrap_or(t),
+                None => t,
+            })
+            .collect();
+        writeln!(conn, "{}", tokens.join(" "))?;
+    }
+    conn.shutdown(Shutdown::Write)?;
+
+    for line in BufReader::new(conn).lines() {
+        let line = line?;
+        match line.split(' ').collect::<Vec<_>>().as_slice() {
+            ["progress", done, total] => eprintln!("warmup: {}/{} files", done, total),
+            [status @ ("done" | "timeout"), done, total, cached] => {
+                eprintln!("warmup {}: {}/{} files processed, {} cached", status, done, total, cached);
+                if !all && *status == "done" && cached != total {
+                    eprintln!("warmup: files over the size limit or excluded by .gitattributes were skipped; pass --all to load them");
+                }
+                return Ok(*status == "done");
+            }
+            _ => bail!("mount: {}", line),
+        }
+    }
+    bail!("mount closed the control connection")
+}
+
+/// Client side of `invalidate`: have the mount drop what the kernel caches for
+/// `paths`, relative to the repository root or absolute under `root`.
+pub fn invalidate(socket_path: &Path, paths: &[String], root: Option<&Path>) -> Result<()> {
+    let mut conn = UnixStream::connect(socket_path)
+        .with_context(|| format!("no mount is listening on {:?}", socket_path))?;
+    writeln!(conn, "invalidate")?;
+    for path in paths {
+        let path = match root.and_then(|r| Path::new(path).strip_prefix(r).ok()) {
+            Some(rel) => rel.to_str().unwrap_or(path),
+            None => path,
+        };
+        writeln!(conn, "{}", path)?;
+    }
+    conn.shutdown(Shutdown::Write)?;
+
+    let mut reply = String::new();
+    BufReader::new(conn).read_line(&mut reply)?;
+    match reply.trim().strip_prefix("done ") {
+        Some(held) => {
+            eprintln!("invalidate: {} of {} paths were cached", held, paths.len());
+            Ok(())
+        }
+        None => bail!("mount: {}", reply.trim()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{FileMode, Signature};
+
+    #[test]
+    fn expands_dependency_lines_and_globs() {
+        let dir = std::env::temp_dir().join(format!("gitfs-control-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let head = {
+            let mut src = repo.treebuilder(None).unwrap();
+            src.insert("x.c", repo.blob(b"x").unwrap(), FileMode::Blob.into()).unwrap();
+            src.insert("y.h", repo.blob(b"y").unwrap(), FileMode::Blob.into()).unwrap();
+            let src = src.write().unwrap();
+            let mut root = repo.treebuilder(None).unwrap();
+            root.insert("src", src, FileMode::Tree.into()).unwrap();
+            let tree = repo.find_tree(root.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+        };
+
+        let lines = ["# deps".to_string(), "out.o: ./main.c \\".to_string(), " src/*.h".to_string()];
+        let paths = expand(&repo, head, &lines);
+        assert_eq!(paths, vec![PathBuf::from("main.c"), PathBuf::from("src/y.h")]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,17 +1,16 @@
 use fuser::{FileType, ReplyDirectory};
 use git2::{ObjectType, Repository};
 use std::path::PathBuf;
-use std::sync::Arc;
 use crate::metrics::debug;
 use crate::node_cache::NodeCache;
-use crate::types::{Node, ROOT_INO, i32_to_filemode};
-use crate::cache::LruCache;
+use crate::types::{Node, ROOT_INO, UNKNOWN_INO};
+use crate::overlay_store::OverlayStore;
 
 pub fn read_directory(
     node: &Node,
     offset: i64,
     node_cache: &NodeCache,
-    overlay: &Arc<LruCache>,
+    overlay_store: &OverlayStore,
     repo: &Repository,
     head: git2::Oid,
     mut reply: ReplyDirectory,
@@ -24,6 +23,25 @@ pub fn read_directory(
         return reply.error(libc::ENOTDIR);
     }
 
+    // Add entries starting from offset
+    let entries = list_directory(node, node_cache, overlay_store, repo, head);
+    for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+        if reply.add(ino, (i + 1) as i64, kind, name) {
+            break;
+        }
+    }
+    
+    reply.ok();
+}
+
+// `.`, `..`, then the git entries and those created through the mount
+fn list_directory(
+    node: &Node,
+    node_cache: &NodeCache,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: git2::Oid,
+) -> Vec<(u64, FileType, String)> {
     let mut entries: Vec<(u64, FileType, String)> = vec![];
     
     // Add . and ..
@@ -39,118 +57,87 @@ pub fn read_directory(
     entries.push((parent_ino, FileType::Directory, "..".to_string()));
 
     // Git entries
-    if let Ok(commit) = repo.find_commit(head) {
-        if let Ok(mut curr_tree) = commit.tree() {
-            let mut valid = true;
-            for comp in node.path.iter() {
-                if let Some(comp_str) = comp.to_str() {
-                    let next_tree = curr_tree.get_name(comp_str)
-                        .and_then(|entry| entry.to_object(repo).ok())
-                        .and_then(|obj| obj.peel_to_tree().ok());
-                    
-                    if let Some(tree) = next_tree {
-                        curr_tree = tree;
-                    } else {
-                        valid = false;
-                        break;
-                    }
-                } else {
-                    valid = false;
-                    break;
-                }
-            }
+    let meta = node_cache.tree_meta();
+    let listing = meta
+        .tree_at(repo, head, &node.path)
+        .and_then(|oid| meta.entries(repo, oid));
+    for e in listing.iter().flat_map(|entries| entries.iter()) {
+        let kind = match e.kind {
+            ObjectType::Tree => FileType::Directory,
+            ObjectType::Blob => FileType::RegularFile,
+            _ => continue,
+        };
 
-            if valid {
-                for e in curr_tree.iter() {
-                    let kind = match e.kind() {
-                        Some(ObjectType::Tree) => FileType::Directory,
-                        Some(ObjectType::Blob) => FileType::RegularFile,
-                        _ => continue,
-                    };
-                    let name = match e.name() {
-                        Some(n) => n.to_string(),
-                        None => continue,
-                    };
-                    
-                    let child_path = node.path.join(&name);
-                    let child_ino = if let Some(ino) = node_cache.get_ino_by_path(&child_path) {
-                        ino
-                    } else {
-                        let ino = node_cache.alloc_ino(&child_path);
-                        let size = if kind == FileType::RegularFile {
-                            e.to_object(repo).ok()
-                                .and_then(|o| o.peel_to_blob().ok())
-                                .map(|b| b.size() as u64)
-                                .unwrap_or(0)
-                        } else {
-                            0
-                        };
-                        let child_node = Node {
-                            ino,
-                            kind,
-                            size,
-                            path: child_path.clone(),
-                            git_mode: Some(i32_to_filemode(e.filemode())),
-                        };
-                        node_cache.insert_node(ino, child_node);
-                        ino
-                    };
-                    
-                    entries.push((child_ino, kind, name));
-                }
-            }
-        }
+        let child_ino = node_cache
+            .get_ino_by_path(&node.path.join(&e.name))
+            .unwrap_or(UNKNOWN_INO);
+
+        entries.push((child_ino, kind, e.name.clone()));
     }
 
-    // Overlay entries - collect them first
-    let mut overlay_entries = Vec::new();
-    overlay.iter(|p, data| {
-        if p.parent() == Some(&node.path) {
-            overlay_entries.push((p.clone(), data.clone()));
-        }
-    });
-    
-    for (p, data) in overlay_entries {
-        let name = p.file_name().unwrap().to_str().unwrap().to_string();
-        
+    // Entries created through the mount
+    for (name, entry) in overlay_store.children(&node.path) {
         if entries.iter().any(|(_, _, n)| n == &name) {
             continue;
         }
         
-        let (child_ino, kind) = if let Some(ino) = node_cache.get_ino_by_path(&p) {
-            if let Some(existing_node) = node_cache.get_node(&ino) {
-                (ino, existing_node.kind)
-            } else {
-                let child_node = Node {
-                    ino,
-                    kind: FileType::RegularFile,
-                    size: data.len() as u64,
-                    path: p.clone(),
-                    git_mode: None,
-                };
-                node_cache.insert_node(ino, child_node);
-                (ino, FileType::RegularFile)
-            }
-        } else {
-            let ino = node_cache.alloc_ino(&p);
-            let child_node = Node {
-                ino,
-                kind: FileType::RegularFile,
-                size: data.len() as u64,
-                path: p.clone(),
-                git_mode: None,
-            };
-            node_cache.insert_node(ino, child_node);
-            (ino, FileType::RegularFile)
+        let child_ino = node_cache
+            .get_ino_by_path(&node.path.join(&name))
+            .unwrap_or(UNKNOWN_INO);
+        entries.push((child_ino, entry.kind, name));
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commit_times::CommitTimes;
+    use crate::overlay_store::OverlayEntry;
+    use crate::types::mknod_kind;
+    use git2::{FileMode, Signature};
+    use std::path::Path;
+
+    #[test]
+    fn special_files_keep_their_kind() {
+        let dir = std::env::temp_dir().join(format!("gitfs-mknod-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let head = {
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("a.txt", repo.blob(b"a").unwrap(), FileMode::Blob.into()).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
         };
-        
-        entries.push((child_ino, kind, name));
-    }    // Add entries starting from offset
-    for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
-        if reply.add(ino, (i + 1) as i64, kind, name) {
-            break;
-        }
+        let store = OverlayStore::new(dir.join("overlay")).unwrap();
+        let cache = NodeCache::new(CommitTimes::new(&repo, head, None).unwrap());
+
+        assert_eq!(mknod_kind(libc::S_IFIFO | 0o644), Some(FileType::NamedPipe));
+        assert_eq!(mknod_kind(libc::S_IFSOCK | 0o755), Some(FileType::Socket));
+        assert_eq!(mknod_kind(libc::S_IFDIR | 0o755), None);
+        store.insert(PathBuf::from("fifo"), OverlayEntry::new(FileType::NamedPipe));
+        let mut tty = OverlayEntry::new(FileType::CharDevice);
+        tty.rdev = 0x0501;
+        store.insert(PathBuf::from("tty"), tty);
+
+        let root = cache.get_node(&ROOT_INO).unwrap();
+        let mut kinds: Vec<(FileType, String)> = list_directory(&root, &cache, &store, &repo, head)
+            .into_iter()
+            .skip(2)
+            .map(|(_, kind, name)| (kind, name))
+            .collect();
+        kinds.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(kinds, vec![
+            (FileType::RegularFile, "a.txt".to_string()),
+            (FileType::NamedPipe, "fifo".to_string()),
+            (FileType::CharDevice, "tty".to_string()),
+        ]);
+
+        let node = cache.lookup_path(Path::new("tty"), &store, &repo, head).unwrap();
+        let attr = cache.node_to_attr(&node, store.get(Path::new("tty")).as_ref());
+        assert_eq!((attr.kind, attr.rdev, attr.size), (FileType::CharDevice, 0x0501, 0));
+        assert!(!store.get(Path::new("fifo")).unwrap().committable());
+        let _ = std::fs::remove_dir_all(&dir);
     }
-    
-    reply.ok();
 }
@@ -1,17 +1,26 @@
-use fuser::{ReplyData, ReplyWrite};
+use fuser::consts::{FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE};
+use fuser::{ReplyData, ReplyLseek, ReplyWrite};
 use git2::Repository;
 use libc::ENOENT;
+use std::fs::File;
+use std::io;
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use crate::metrics::{debug, Metrics};
 use crate::node_cache::NodeCache;
 use crate::types::Node;
-use crate::cache::LruCache;
+use crate::cache::{LruCache, Prefetched};
+use crate::overlay_store::{OverlayEntry, OverlayStore};
 
+#[allow(clippy::too_many_arguments)]
 pub fn read_file(
     node: &Node,
     offset: i64,
     size: u32,
+    overlay_store: &OverlayStore,
     overlay: &Arc<LruCache>,
     repo: &Repository,
     head: git2::Oid,
@@ -21,10 +30,45 @@ pub fn read_file(
     debug!("[READ] ino={}, offset={}, size={}", node.ino, offset, size);
     debug!("[READ] path={:?}", node.path);
 
-    // Check overlay first
-    if let Some(data) = overlay.get(&node.path) {
+    // Content written through the mount
+    if let Some(file) = overlay_store.data_file(&node.path) {
+        match file.and_then(|f| read_range(&f, offset as u64, size as usize)) {
+            Ok(data) => {
+                debug!("[READ] reading from overlay store, {} bytes", data.len());
+                reply.data(&data);
+            }
+            Err(e) => {
+                debug!("[READ] overlay store read failed: {}", e);
+                reply.error(libc::EIO);
+            }
+        }
+        return;
+    }
+
+    // A git blob shared through copy_file_range
+    if let Some(oid) = overlay_store.get(&node.path).and_then(|e| e.base_oid) {
+        match repo.find_blob(oid) {
+            Ok(blob) => reply.data(slice_at(blob.content(), offset, size)),
+            Err(e) => {
+                debug!("[READ] failed to find blob {}: {}", oid, e);
+                reply.error(libc::EIO);
+            }
+        }
+        return;
+    }
+
+    // Then the blob cache
+    if let Some((data, prefetched)) = overlay.get(&node.path) {
         debug!("[READ] reading from overlay, len={}", data.len());
-        let off = offset as usize;
+        let hits = match prefetched {
+            Some(Prefetched::History) => Some(&metrics.history_hits),
+            Some(Prefetched::Predicted) => Some(&metrics.predicted_hits),
+            _ => None,
+        };
+        if let Some(hits) = hits {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+        let off = usize::min(offset as usize, data.len());
         let end = usize::min(off + size as usize, data.len());
         reply.data(&data[off..end]);
         return;
@@ -89,7 +133,7 @@ pub fn read_file(
     };
 
     let data = blob.content();
-    let off = offset as usize;
+    let off = usize::min(offset as usize, data.len());
     let end = usize::min(off + size as usize, data.len());
     debug!("[READ] returning {} bytes", end - off);
     
@@ -100,64 +144,564 @@ pub fn read_file(
     }
     
     reply.data(&data[off..end]);
+    // Later chunks of the same read come from the cache
+    overlay.insert(node.path.clone(), data.to_vec());
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn write_file(
     ino: u64,
     offset: i64,
     data: &[u8],
     node_cache: &NodeCache,
-    overlay: &Arc<LruCache>,
+    overlay_store: &OverlayStore,
     repo: &Repository,
     head: git2::Oid,
     reply: ReplyWrite,
 ) {
     debug!("[WRITE] ino={}, offset={}, len={}", ino, offset, data.len());
     
-    if let Some(file) = node_cache.get_node(&ino) {
-        debug!("[WRITE] path={:?}", file.path);
-        let path = &file.path;
-        
-        // Prefetch original content from git if not in overlay yet
-        if !overlay.contains_key(path) && offset == 0 {
-            
-            if let Ok(commit) = repo.find_commit(head) {
-                if let Ok(mut curr_tree) = commit.tree() {
-                    if let Some(parent) = path.parent() {
-                        for c in parent.iter() {
-                            if let Some(comp_str) = c.to_str() {
-                                if let Some(next_tree) = curr_tree.get_name(comp_str)
-                                    .and_then(|e| e.to_object(repo).ok())
-                                    .and_then(|o| o.peel_to_tree().ok()) {
-                                    curr_tree = next_tree;
-                                }
-                            }
-                        }
-                    }
-                    
-                    if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
-                        if let Some(blob) = curr_tree.get_name(name)
-                            .and_then(|e| e.to_object(repo).ok())
-                            .and_then(|o| o.peel_to_blob().ok()) {
-                            overlay.insert(path.clone(), blob.content().to_vec());
-                        }
-                    }
+    match write_to_overlay(ino, offset, data, node_cache, overlay_store, repo, head) {
+        Ok(size) => {
+            debug!("[WRITE] wrote {} bytes, file size now {}", data.len(), size);
+            reply.written(data.len() as u32);
+        }
+        Err(e) => {
+            debug!("[WRITE] failed: {}", e);
+            reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+    }
+}
+
+/// Write `data` at `offset` into the overlay copy of `ino` and keep the node's
+/// size in step. Returns the new file size.
+pub fn write_to_overlay(
+    ino: u64,
+    offset: i64,
+    data: &[u8],
+    node_cache: &NodeCache,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: git2::Oid,
+) -> io::Result<u64> {
+    let file = node_cache.get_node(&ino).ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+    debug!("[WRITE] path={:?}", file.path);
+    
+    copy_up(&file.path, overlay_store, repo, head, false)?;
+    let backing = overlay_store.data_file(&file.path).ok_or_else(|| io::Error::from_raw_os_error(ENOENT))??;
+    backing.write_all_at(data, offset as u64)?;
+
+    let size = overlay_store.record_usage(&file.path, &backing)?;
+    node_cache.set_size(ino, size);
+    Ok(size)
+}
+
+/// Resize the overlay copy of `ino` to `size` bytes and keep the node's size in step.
+/// Growing leaves a hole rather than writing zeros.
+pub fn truncate_overlay(
+    ino: u64,
+    size: u64,
+    node_cache: &NodeCache,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: git2::Oid,
+) -> io::Result<u64> {
+    let file = node_cache.get_node(&ino).ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+    // Nothing of the original survives truncation to zero, so there is nothing to copy
+    copy_up(&file.path, overlay_store, repo, head, size == 0)?;
+    let backing = overlay_store.data_file(&file.path).ok_or_else(|| io::Error::from_raw_os_error(ENOENT))??;
+    backing.set_len(size)?;
+    overlay_store.record_usage(&file.path, &backing)?;
+    node_cache.set_size(ino, size);
+    Ok(size)
+}
+
+/// Copy the original git blob for `path` into the overlay ahead of its first
+/// modification, whatever offset that modification is at. The copy is built in
+/// full and published in one step, so concurrent readers see either the
+/// original blob or the complete copy, never a partial or zero-filled one. With
+/// `truncate` the content is about to be discarded, so the copy starts empty.
+pub fn copy_up(path: &Path, overlay_store: &OverlayStore, repo: &Repository, head: git2::Oid, truncate: bool) -> io::Result<()> {
+    if overlay_store.has_data(path) {
+        return Ok(());
+    }
+    if truncate {
+        debug!("[COPY-UP] {:?} (truncated)", path);
+        return overlay_store.replace_data(path, &[]);
+    }
+
+    let content = match overlay_store.get(path).and_then(|e| e.base_oid) {
+        Some(oid) => repo.find_blob(oid).ok().map(|b| b.content().to_vec()),
+        None => git_blob(repo, head, path),
+    }
+    .unwrap_or_default();
+    debug!("[COPY-UP] {:?} ({} bytes)", path, content.len());
+    overlay_store.replace_data(path, &content)
+}
+
+/// Server-side copy_file_range. Copying a whole unmodified git blob over an empty
+/// or shorter destination with no hard links only records the blob's Oid on the destination; other
+/// copies go backing file to backing file without passing through the kernel.
+/// At most `u32::MAX` bytes are copied per call.
+#[allow(clippy::too_many_arguments)]
+pub fn copy_range(
+    ino_in: u64,
+    offset_in: u64,
+    ino_out: u64,
+    offset_out: u64,
+    len: u64,
+    node_cache: &NodeCache,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: git2::Oid,
+) -> io::Result<u64> {
+    let not_found = || io::Error::from_raw_os_error(ENOENT);
+    let src = node_cache.get_node(&ino_in).ok_or_else(not_found)?;
+    let dst = node_cache.get_node(&ino_out).ok_or_else(not_found)?;
+    debug!("[COPY-RANGE] {:?}@{} -> {:?}@{}, len={}", src.path, offset_in, dst.path, offset_out, len);
+
+    if offset_in >= src.size {
+        return Ok(0);
+    }
+    // The reply counts bytes in 32 bits; the caller asks again for the rest
+    let len = len.min(src.size - offset_in).min(u32::MAX as u64);
+
+    if let Some(oid) = clean_blob_oid(&src, overlay_store, repo, head)
+        && offset_in == 0
+        && offset_out == 0
+        && len == src.size
+        && dst.size <= src.size
+        && overlay_store.links_of(&dst.path).is_empty()
+    {
+        debug!("[COPY-RANGE] sharing blob {}", oid);
+        overlay_store.reference_blob(&dst.path, oid);
+        node_cache.set_size(ino_out, src.size);
+        return Ok(len);
+    }
+
+    copy_up(&dst.path, overlay_store, repo, head, false)?;
+    let out = overlay_store.data_file(&dst.path).ok_or_else(not_found)??;
+
+    let copied = match overlay_store.data_file(&src.path) {
+        Some(input) => {
+            let input = input?;
+            let (mut off_in, mut off_out) = (offset_in as i64, offset_out as i64);
+            let n = unsafe {
+                libc::copy_file_range(input.as_raw_fd(), &mut off_in, out.as_raw_fd(), &mut off_out, len as usize, 0)
+            };
+            if n < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            n as u64
+        }
+        None => {
+            let content = match overlay_store.get(&src.path).and_then(|e| e.base_oid) {
+                Some(oid) => repo.find_blob(oid).ok().map(|b| b.content().to_vec()),
+                None => git_blob(repo, head, &src.path),
+            }
+            .ok_or_else(not_found)?;
+            let data = slice_at(&content, offset_in as i64, len as u32);
+            out.write_all_at(data, offset_out)?;
+            data.len() as u64
+        }
+    };
+
+    node_cache.set_size(ino_out, overlay_store.record_usage(&dst.path, &out)?);
+    Ok(copied)
+}
+
+/// fallocate on the overlay copy, including FALLOC_FL_PUNCH_HOLE. The backing
+/// file's filesystem does the work, so unsupported modes fail the same way.
+#[allow(clippy::too_many_arguments)]
+pub fn allocate(
+    ino: u64,
+    offset: i64,
+    length: i64,
+    mode: i32,
+    node_cache: &NodeCache,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: git2::Oid,
+) -> io::Result<u64> {
+    let file = node_cache.get_node(&ino).ok_or_else(|| io::Error::from_raw_os_error(ENOENT))?;
+    debug!("[FALLOCATE] path={:?}, offset={}, length={}, mode={:#x}", file.path, offset, length, mode);
+
+    copy_up(&file.path, overlay_store, repo, head, false)?;
+    let backing = overlay_store.data_file(&file.path).ok_or_else(|| io::Error::from_raw_os_error(ENOENT))??;
+    if unsafe { libc::fallocate(backing.as_raw_fd(), mode, offset, length) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let size = overlay_store.record_usage(&file.path, &backing)?;
+    node_cache.set_size(ino, size);
+    Ok(size)
+}
+
+/// FOPEN_* flags for an open of `node`. Unmodified git content never changes
+/// for a given Oid, so the kernel may keep its page cache across opens; O_DIRECT
+/// opens bypass the page cache entirely.
+pub fn open_flags(node: &Node, flags: i32, overlay_store: &OverlayStore, repo: &Repository, head: git2::Oid) -> u32 {
+    if flags & libc::O_DIRECT != 0 {
+        FOPEN_DIRECT_IO
+    } else if clean_blob_oid(node, overlay_store, repo, head).is_some() {
+        FOPEN_KEEP_CACHE
+    } else {
+        0
+    }
+}
+
+// Oid of the git blob `node` currently reads as, if it has no overlay copy of its own
+fn clean_blob_oid(node: &Node, overlay_store: &OverlayStore, repo: &Repository, head: git2::Oid) -> Option<git2::Oid> {
+    match overlay_store.get(&node.path) {
+        Some(entry) if entry.data_id.is_some() => None,
+        Some(OverlayEntry { base_oid: Some(oid), .. }) => Some(oid),
+        _ => repo.find_commit(head).ok()?.tree().ok()?.get_path(&node.path).ok().map(|e| e.id()),
+    }
+}
+
+fn slice_at(data: &[u8], offset: i64, size: u32) -> &[u8] {
+    let off = usize::min(offset as usize, data.len());
+    let end = usize::min(off + size as usize, data.len());
+    &data[off..end]
+}
+
+/// SEEK_DATA / SEEK_HOLE. Overlay content answers from its sparse backing file;
+/// git content has no holes.
+pub fn seek(node: &Node, offset: i64, whence: i32, overlay_store: &OverlayStore, reply: ReplyLseek) {
+    debug!("[LSEEK] path={:?}, offset={}, whence={}", node.path, offset, whence);
+
+    if let Some(file) = overlay_store.data_file(&node.path) {
+        let file = match file {
+            Ok(f) => f,
+            Err(e) => return reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        };
+        let pos = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+        if pos < 0 {
+            return reply.error(io::Error::last_os_error().raw_os_error().unwrap_or(libc::EIO));
+        }
+        return reply.offset(pos);
+    }
+
+    let size = node.size as i64;
+    match whence {
+        libc::SEEK_DATA if offset < size => reply.offset(offset),
+        libc::SEEK_HOLE if offset < size => reply.offset(size),
+        libc::SEEK_DATA | libc::SEEK_HOLE => reply.error(libc::ENXIO),
+        _ => reply.error(libc::EINVAL),
+    }
+}
+
+fn read_range(file: &File, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; size];
+    let mut filled = 0;
+    while filled < size {
+        match file.read_at(&mut buf[filled..], offset + filled as u64) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    buf.truncate(filled);
+    Ok(buf)
+}
+
+fn git_blob(repo: &Repository, head: git2::Oid, path: &Path) -> Option<Vec<u8>> {
+    let tree = repo.find_commit(head).ok()?.tree().ok()?;
+    let blob = tree.get_path(path).ok()?.to_object(repo).ok()?.peel_to_blob().ok()?;
+    Some(blob.content().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commit_times::CommitTimes;
+    use fuser::FileType;
+    use git2::{FileMode, Signature};
+    use std::path::PathBuf;
+
+    struct Fixture {
+        dir: PathBuf,
+        repo: Repository,
+        head: git2::Oid,
+        node_cache: NodeCache,
+        overlay_store: OverlayStore,
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    impl Fixture {
+        // Repository with a single commit holding `files` at the top level
+        fn new(name: &str, files: &[(&str, &[u8])]) -> Self {
+            let dir = std::env::temp_dir().join(format!("gitfs-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let repo = Repository::init(&dir).unwrap();
+
+            let head = {
+                let mut builder = repo.treebuilder(None).unwrap();
+                for (name, content) in files {
+                    let oid = repo.blob(content).unwrap();
+                    builder.insert(name, oid, FileMode::Blob.into()).unwrap();
                 }
+                let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+                let sig = Signature::now("test", "test@example.com").unwrap();
+                repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+            };
+
+            let times = CommitTimes::new(&repo, head, None).unwrap();
+            Fixture {
+                overlay_store: OverlayStore::new(dir.join("overlay")).unwrap(),
+                dir,
+                repo,
+                head,
+                node_cache: NodeCache::new(times),
             }
         }
-        
-        let mut content = overlay.get(path).unwrap_or_else(Vec::new);
 
-        if content.len() < offset as usize + data.len() {
-            content.resize(offset as usize + data.len(), 0);
+        fn lookup(&self, path: &str) -> u64 {
+            self.node_cache
+                .lookup_path(Path::new(path), &self.overlay_store, &self.repo, self.head)
+                .unwrap()
+                .ino
         }
 
-        content[offset as usize..offset as usize + data.len()].copy_from_slice(data);
-        overlay.insert(path.clone(), content);
-        debug!("[WRITE] wrote {} bytes", data.len());
-        reply.written(data.len() as u32);
-    } else {
-        debug!("[WRITE] inode not found");
-        reply.error(libc::ENOENT);
+        fn create(&self, path: &str) -> u64 {
+            self.overlay_store.insert(PathBuf::from(path), OverlayEntry::new(FileType::RegularFile));
+            self.overlay_store.replace_data(Path::new(path), &[]).unwrap();
+            self.lookup(path)
+        }
+
+        fn write(&self, ino: u64, offset: i64, data: &[u8]) -> u64 {
+            write_to_overlay(ino, offset, data, &self.node_cache, &self.overlay_store, &self.repo, self.head).unwrap()
+        }
+
+        fn truncate(&self, ino: u64, size: u64) {
+            truncate_overlay(ino, size, &self.node_cache, &self.overlay_store, &self.repo, self.head).unwrap();
+        }
+
+        fn stat_size(&self, ino: u64) -> u64 {
+            let node = self.node_cache.get_node(&ino).unwrap();
+            self.node_cache.node_to_attr(&node, self.overlay_store.get(&node.path).as_ref()).size
+        }
+
+        fn content(&self, path: &str) -> Vec<u8> {
+            let file = self.overlay_store.data_file(Path::new(path)).unwrap().unwrap();
+            let len = file.metadata().unwrap().len() as usize;
+            read_range(&file, 0, len).unwrap()
+        }
+    }
+
+    #[test]
+    fn write_updates_size() {
+        let fx = Fixture::new("write", &[]);
+        let ino = fx.create("new.txt");
+        assert_eq!(fx.stat_size(ino), 0);
+
+        assert_eq!(fx.write(ino, 0, b"hello"), 5);
+        assert_eq!(fx.stat_size(ino), 5);
+    }
+
+    #[test]
+    fn append_grows_size() {
+        let fx = Fixture::new("append", &[]);
+        let ino = fx.create("log.txt");
+
+        fx.write(ino, 0, b"hello");
+        fx.write(ino, 5, b" world");
+        assert_eq!(fx.stat_size(ino), 11);
+        assert_eq!(fx.content("log.txt"), b"hello world");
+    }
+
+    #[test]
+    fn overwrite_inside_tracked_file_keeps_size() {
+        let fx = Fixture::new("overwrite", &[("a.txt", b"abcdef")]);
+        let ino = fx.lookup("a.txt");
+        assert_eq!(fx.stat_size(ino), 6);
+
+        fx.write(ino, 0, b"XY");
+        assert_eq!(fx.stat_size(ino), 6);
+        assert_eq!(fx.content("a.txt"), b"XYcdef");
+    }
+
+    #[test]
+    fn write_past_start_of_tracked_file_copies_up() {
+        let fx = Fixture::new("copy-up", &[("db", b"0123456789")]);
+        let ino = fx.lookup("db");
+
+        fx.write(ino, 4, b"ab");
+        assert_eq!(fx.content("db"), b"0123ab6789");
+        assert_eq!(fx.stat_size(ino), 10);
+    }
+
+    #[test]
+    fn truncate_tracked_file_keeps_prefix() {
+        let fx = Fixture::new("truncate-tracked", &[("a.txt", b"abcdef")]);
+        let ino = fx.lookup("a.txt");
+
+        fx.truncate(ino, 3);
+        assert_eq!(fx.content("a.txt"), b"abc");
+    }
+
+    #[test]
+    fn truncate_to_zero_starts_an_empty_copy() {
+        let fx = Fixture::new("truncate-zero", &[("a.txt", b"abcdef")]);
+        let ino = fx.lookup("a.txt");
+
+        fx.truncate(ino, 0);
+        assert_eq!(fx.content("a.txt"), b"");
+        assert_eq!(fx.stat_size(ino), 0);
+        fx.write(ino, 0, b"xy");
+        assert_eq!(fx.content("a.txt"), b"xy");
+    }
+
+    #[test]
+    fn truncate_shrinks_and_extends() {
+        let fx = Fixture::new("truncate", &[]);
+        let ino = fx.create("t.bin");
+        fx.write(ino, 0, b"0123456789");
+
+        fx.truncate(ino, 4);
+        assert_eq!(fx.stat_size(ino), 4);
+        assert_eq!(fx.content("t.bin"), b"0123");
+
+        fx.truncate(ino, 6);
+        assert_eq!(fx.stat_size(ino), 6);
+        assert_eq!(fx.content("t.bin"), b"0123\0\0");
+    }
+
+    #[test]
+    fn large_sparse_write_keeps_hole() {
+        let fx = Fixture::new("sparse", &[]);
+        let ino = fx.create("big.img");
+        let offset = 64 << 20;
+
+        assert_eq!(fx.write(ino, offset, b"tail"), offset as u64 + 4);
+        let file = fx.overlay_store.data_file(Path::new("big.img")).unwrap().unwrap();
+        let data_start = unsafe { libc::lseek(file.as_raw_fd(), 0, libc::SEEK_DATA) };
+        assert!(data_start > 0);
+        assert_eq!(read_range(&file, offset as u64, 4).unwrap(), b"tail");
+    }
+
+    #[test]
+    fn copy_of_clean_blob_shares_oid() {
+        let fx = Fixture::new("copy-share", &[("src.bin", b"0123456789")]);
+        let src = fx.lookup("src.bin");
+        let dst = fx.create("dst.bin");
+
+        let copied = copy_range(src, 0, dst, 0, 1 << 20, &fx.node_cache, &fx.overlay_store, &fx.repo, fx.head).unwrap();
+        assert_eq!(copied, 10);
+        assert_eq!(fx.stat_size(dst), 10);
+
+        let entry = fx.overlay_store.get(Path::new("dst.bin")).unwrap();
+        assert!(entry.data_id.is_none());
+        assert_eq!(entry.base_oid, Some(fx.repo.blob(b"0123456789").unwrap()));
+
+        // First write copies the shared blob up
+        fx.write(dst, 2, b"ab");
+        assert_eq!(fx.content("dst.bin"), b"01ab456789");
+    }
+
+    #[test]
+    fn partial_copy_writes_data() {
+        let fx = Fixture::new("copy-partial", &[("src.bin", b"0123456789")]);
+        let src = fx.lookup("src.bin");
+        let dst = fx.create("dst.bin");
+        fx.write(dst, 0, b"xxxx");
+
+        let copied = copy_range(src, 6, dst, 2, 10, &fx.node_cache, &fx.overlay_store, &fx.repo, fx.head).unwrap();
+        assert_eq!(copied, 4);
+        assert_eq!(fx.content("dst.bin"), b"xx6789");
+        assert_eq!(fx.stat_size(dst), 6);
+    }
+
+    #[test]
+    fn punch_hole_keeps_size() {
+        let fx = Fixture::new("punch", &[]);
+        let ino = fx.create("f.bin");
+        fx.write(ino, 0, &[1u8; 8192]);
+
+        let mode = libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE;
+        match allocate(ino, 0, 4096, mode, &fx.node_cache, &fx.overlay_store, &fx.repo, fx.head) {
+            Ok(size) => {
+                assert_eq!(size, 8192);
+                assert_eq!(&fx.content("f.bin")[..4096], &[0u8; 4096][..]);
+            }
+            // Backing filesystem without hole punching
+            Err(e) => assert_eq!(e.raw_os_error(), Some(libc::EOPNOTSUPP)),
+        }
+    }
+
+    #[test]
+    fn page_cache_is_kept_for_clean_blobs_only() {
+        let fx = Fixture::new("open-flags", &[("a.txt", b"abc"), ("b.txt", b"abc")]);
+        let flags = |path: &str, open: i32| {
+            let ino = fx.lookup(path);
+            let node = fx.node_cache.get_node(&ino).unwrap();
+            open_flags(&node, open, &fx.overlay_store, &fx.repo, fx.head)
+        };
+        assert_eq!(flags("a.txt", libc::O_RDONLY), FOPEN_KEEP_CACHE);
+        assert_eq!(flags("a.txt", libc::O_RDONLY | libc::O_DIRECT), FOPEN_DIRECT_IO);
+
+        let ino = fx.lookup("a.txt");
+        fx.write(ino, 0, b"x");
+        assert_eq!(flags("a.txt", libc::O_RDONLY), 0);
+
+        // A copy sharing the blob is as immutable as the blob
+        let dst = fx.create("c.txt");
+        copy_range(fx.lookup("b.txt"), 0, dst, 0, 3, &fx.node_cache, &fx.overlay_store, &fx.repo, fx.head).unwrap();
+        assert_eq!(flags("c.txt", libc::O_RDONLY), FOPEN_KEEP_CACHE);
+    }
+
+    #[test]
+    fn size_survives_forget() {
+        let fx = Fixture::new("forget", &[("a.txt", b"abc")]);
+        let ino = fx.lookup("a.txt");
+        fx.write(ino, 3, b"defg");
+        fx.overlay_store.touch(Path::new("a.txt"));
+
+        fx.node_cache.forget(ino, 1);
+        assert!(fx.node_cache.get_node(&ino).is_none());
+
+        let ino = fx.lookup("a.txt");
+        assert_eq!(fx.stat_size(ino), 7);
+    }
+
+    #[test]
+    fn hard_link_shares_inode_and_content() {
+        let fx = Fixture::new("link", &[("a.txt", b"abc")]);
+        let ino = fx.lookup("a.txt");
+        copy_up(Path::new("a.txt"), &fx.overlay_store, &fx.repo, fx.head, false).unwrap();
+        fx.overlay_store.link(Path::new("a.txt"), Path::new("b.txt")).unwrap();
+
+        assert_eq!(fx.lookup("b.txt"), ino);
+        fx.write(ino, 3, b"def");
+        assert_eq!(fx.content("b.txt"), b"abcdef");
+        assert_eq!(fx.overlay_store.get(Path::new("b.txt")).unwrap().nlink, 2);
+
+        // Forgotten and looked up again, both names still share one inode
+        fx.node_cache.forget(ino, 2);
+        let ino = fx.lookup("b.txt");
+        assert_eq!(fx.lookup("a.txt"), ino);
+        assert_eq!(fx.stat_size(ino), 6);
+    }
+
+    #[test]
+    fn unlinking_a_name_keeps_the_other() {
+        let fx = Fixture::new("unlink-link", &[]);
+        let ino = fx.create("a.txt");
+        fx.write(ino, 0, b"data");
+        fx.overlay_store.link(Path::new("a.txt"), Path::new("b.txt")).unwrap();
+
+        fx.overlay_store.remove(Path::new("a.txt"));
+        fx.node_cache.remove_link(Path::new("a.txt"), Path::new("b.txt"));
+
+        assert_eq!(fx.node_cache.get_ino_by_path(Path::new("b.txt")), Some(ino));
+        assert_eq!(fx.overlay_store.get(Path::new("b.txt")).unwrap().nlink, 1);
+        fx.write(ino, 4, b"!");
+        assert_eq!(fx.content("b.txt"), b"data!");
     }
 }
@@ -3,19 +3,42 @@ use fuser::*;
 use git2::{Repository, FileMode};
 use libc::ENOENT;
 use std::{
+    collections::{HashMap, HashSet},
     ffi::OsStr,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{mpsc, Arc, Mutex, OnceLock},
     time::{Duration, SystemTime},
 };
 
-use crate::types::Node;
+use crate::types::{Node, mknod_kind};
 use crate::metrics::{debug, Metrics};
 use crate::node_cache::NodeCache;
 use crate::cache::LruCache;
-use crate::{prefetch, file_ops, dir_ops};
+use crate::overlay_store::{AttrChange, OverlayEntry, OverlayStore};
+use crate::commit_times::CommitTimes;
+use crate::config::Config;
+use crate::locks::{Lock, LockTable};
+use crate::invalidate::Invalidator;
+use crate::trace::AccessTrace;
+use crate::profile::Profile;
+use crate::prefetch::{ForegroundTimer, Prefetcher, Priority};
+use crate::control::{Control, ControlSocket, CONTROL_SOCKET};
+use crate::{access, head_watch, file_ops, dir_ops, xattr_ops};
 
 const TTL: Duration = Duration::from_secs(1);
+// For unmodified git content
+const GIT_TTL: Duration = Duration::from_secs(3600);
+
+// Negotiated in init; the kernel caps both at what it supports
+const MAX_WRITE: u32 = 1024 * 1024;
+const MAX_READAHEAD: u32 = 1024 * 1024;
+
+// Backing files for overlay content, inside the repository's .git directory
+const OVERLAY_DIR: &str = "fuse_overlay";
+// Access trace kept across mounts, also inside .git
+const TRACE_FILE: &str = "fuse_access_trace";
+// Default location of the prefetch profile
+const PROFILE_FILE: &str = "fuse_prefetch_profile";
 
 // Default cache limits: 2048MB and 50000 files
 const DEFAULT_MAX_CACHE_BYTES: usize = 2048 * 1024 * 1024;
@@ -25,63 +48,326 @@ pub struct GitFsOverlay {
     repo: Repository,
     repo_path: PathBuf,
     head: git2::Oid,
-    node_cache: NodeCache,
+    node_cache: Arc<NodeCache>,
     overlay: Arc<LruCache>,
+    overlay_store: OverlayStore,
+    // Shared with the control socket
+    prefetcher: Arc<Prefetcher>,
+    // None until init, or when another mount owns the socket
+    control: Option<ControlSocket>,
+    trace: Option<AccessTrace>,
+    // Shared with threads parked on blocking lock requests
+    locks: Arc<LockTable>,
+    // Entries in HEAD's tree, counted on the first statfs
+    head_entries: OnceLock<u64>,
+    // Off when mounted with default_permissions, where the kernel checks access itself
+    check_access: bool,
+    // File handles opened for writing; with writeback caching the kernel sends
+    // cached writes without the opener's flags, so the handle is what counts
+    writable_handles: HashSet<u64>,
+    // Granted at init; changes the open flags the kernel sends
+    writeback: bool,
+    // Open handles per inode; an unlinked file keeps its content until the last is released
+    open_counts: HashMap<u64, u32>,
+    next_fh: u64,
+    invalidator: Invalidator,
+    // Per-path commit times report here when known; None with them off
+    times_resolved: Option<mpsc::Sender<PathBuf>>,
+    notifier: Arc<OnceLock<Notifier>>,
+    // Set by the HEAD watcher, applied at the start of the next request
+    pending_head: Arc<Mutex<Option<git2::Oid>>>,
+    // `head`, for the control socket
+    served_head: Arc<Mutex<git2::Oid>>,
+    config: Config,
     metrics: Arc<Metrics>,
 }
 
 impl GitFsOverlay {
     pub fn new(repo_path: &Path) -> Result<Self> {
-        let repo = Repository::open(repo_path)?;
-        let head = repo.head()?.target().context("invalid HEAD")?;
-
-        Ok(GitFsOverlay {
-            repo,
-            repo_path: repo_path.to_path_buf(),
-            head,
-            node_cache: NodeCache::new(),
-            overlay: Arc::new(LruCache::new(DEFAULT_MAX_CACHE_BYTES, DEFAULT_MAX_CACHE_ENTRIES)),
-            metrics: Arc::new(Metrics::default()),
-        })
+        Self::with_cache_limits(repo_path, DEFAULT_MAX_CACHE_BYTES, DEFAULT_MAX_CACHE_ENTRIES)
     }
 
-    #[allow(dead_code)]
     pub fn with_cache_limits(repo_path: &Path, max_bytes: usize, max_entries: usize) -> Result<Self> {
         let repo = Repository::open(repo_path)?;
         let head = repo.head()?.target().context("invalid HEAD")?;
+        let config = Config::from_env();
+        // Attributes handed out before a path's commit time was known are dropped once it is
+        let (times_resolved, resolved_paths) = mpsc::channel();
+        let times_resolved = config.path_mtime.then_some(times_resolved);
+        let times = CommitTimes::new(&repo, head, times_resolved.clone())?;
+        let overlay_store = OverlayStore::new(repo.path().join(OVERLAY_DIR))
+            .context("failed to create overlay store")?;
+        let notifier = Arc::new(OnceLock::new());
+        let trace = if config.access_trace {
+            AccessTrace::open(&repo.path().join(TRACE_FILE))
+                .map_err(|e| debug!("[TRACE] disabled: {}", e))
+                .ok()
+        } else {
+            None
+        };
+        let node_cache = Arc::new(NodeCache::new(times));
+        let invalidator = Invalidator::spawn(notifier.clone());
+        invalidator.paths(resolved_paths, Arc::downgrade(&node_cache));
+        let overlay = Arc::new(LruCache::new(max_bytes, config.prefetch_cache_bytes, max_entries, config.prefetch_max_blob as usize));
+        let metrics = Arc::new(Metrics::default());
+        let profile_path = config
+            .prefetch_profile
+            .clone()
+            .unwrap_or_else(|| repo.path().join(PROFILE_FILE));
+        let profile = Profile::load(&profile_path)
+            .with_context(|| format!("failed to read prefetch profile {:?}", profile_path))?;
+        let prefetcher = Arc::new(Prefetcher::new(
+            repo_path.to_path_buf(),
+            &config,
+            profile,
+            overlay.clone(),
+            node_cache.tree_meta().clone(),
+            metrics.clone(),
+        ));
 
         Ok(GitFsOverlay {
             repo,
             repo_path: repo_path.to_path_buf(),
             head,
-            node_cache: NodeCache::new(),
-            overlay: Arc::new(LruCache::new(max_bytes, max_entries)),
-            metrics: Arc::new(Metrics::default()),
+            node_cache,
+            overlay,
+            overlay_store,
+            prefetcher,
+            control: None,
+            trace,
+            locks: Arc::new(LockTable::default()),
+            head_entries: OnceLock::new(),
+            check_access: !config.default_permissions,
+            writable_handles: HashSet::new(),
+            writeback: false,
+            open_counts: HashMap::new(),
+            next_fh: 1,
+            invalidator,
+            times_resolved,
+            notifier,
+            pending_head: Arc::new(Mutex::new(None)),
+            served_head: Arc::new(Mutex::new(head)),
+            config,
+            metrics,
         })
     }
 
-    fn prefetch_directory(&self, dir_path: &Path) {
-        prefetch::prefetch_directory(
-            self.repo_path.clone(),
-            dir_path.to_path_buf(),
-            self.head,
-            self.overlay.clone(),
-            self.metrics.clone(),
-        );
+    /// Filled in with the session's notifier once mounted.
+    pub fn notifier_slot(&self) -> Arc<OnceLock<Notifier>> {
+        self.notifier.clone()
+    }
+
+    // Start of every request: time it for prefetch backoff and apply a HEAD switch
+    fn begin(&mut self) -> ForegroundTimer {
+        let timer = self.prefetcher.foreground();
+        self.sync_head();
+        timer
+    }
+
+    // Move to the commit the HEAD watcher saw, if HEAD moved since the last request
+    fn sync_head(&mut self) {
+        let Some(head) = self.pending_head.lock().unwrap().take() else { return };
+        if head == self.head {
+            return;
+        }
+        let times = match CommitTimes::new(&self.repo, head, self.times_resolved.clone()) {
+            Ok(t) => t,
+            Err(e) => {
+                debug!("[HEAD] cannot switch to {}: {}", head, e);
+                return;
+            }
+        };
+
+        debug!("[HEAD] switching {} -> {}", self.head, head);
+        self.head = head;
+        *self.served_head.lock().unwrap() = head;
+        self.head_entries = OnceLock::new();
+        // Clean blobs are cached by path, so none of them hold for the new tree
+        self.prefetcher.cancel_all();
+        self.overlay.clear();
+        self.node_cache.switch_head(times, &self.overlay_store, &self.repo, head);
+        head_watch::invalidate_all(&self.node_cache, &self.invalidator);
+        self.prefetcher.warm_profile(head);
+        self.prefetcher.warm_history(head, self.config.prefetch_history);
+    }
+
+    // Git content only changes when HEAD moves, and that invalidates the kernel's caches
+    fn ttl(&self, node: &Node) -> Duration {
+        // Stand-in times are replaced, and invalidated, once the history walk finds the real one
+        if self.overlay_store.get(&node.path).is_some() || self.node_cache.time_pending(&node.path) {
+            TTL
+        } else {
+            GIT_TTL
+        }
+    }
+
+    fn attr(&self, node: &Node) -> FileAttr {
+        self.node_cache.node_to_attr(node, self.overlay_store.get(&node.path).as_ref())
+    }
+
+    fn check(&self, req: &Request<'_>, node: &Node, mask: i32) -> Result<(), libc::c_int> {
+        if !self.check_access || access::allowed(&self.attr(node), &caller(req), mask) {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+
+    // Removing `path` from `parent` (unlink, rmdir, or the source and target of rename)
+    fn check_remove(&self, req: &Request<'_>, parent: &Node, path: &Path) -> Result<(), libc::c_int> {
+        if !self.check_access {
+            return Ok(());
+        }
+        let child = self.node_cache
+            .get_ino_by_path(path)
+            .and_then(|ino| self.node_cache.get_node(&ino))
+            .map(|n| self.attr(&n));
+        if access::may_remove(&self.attr(parent), child.as_ref(), &caller(req)) {
+            Ok(())
+        } else {
+            Err(libc::EACCES)
+        }
+    }
+
+    // chmod, chown and setting explicit times are reserved to the owner
+    fn check_owner(&self, req: &Request<'_>, node: &Node) -> Result<(), libc::c_int> {
+        if !self.check_access || req.uid() == 0 || req.uid() == self.attr(node).uid {
+            Ok(())
+        } else {
+            Err(libc::EPERM)
+        }
+    }
+
+    fn open_handle(&mut self, ino: u64, flags: i32) -> u64 {
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        *self.open_counts.entry(ino).or_default() += 1;
+        if flags & libc::O_ACCMODE != libc::O_RDONLY {
+            self.writable_handles.insert(fh);
+        }
+        fh
+    }
+
+    // Queue what usually follows an open of `path` on earlier runs
+    fn predict_after(&self, path: &Path) {
+        let Some(trace) = &self.trace else { return };
+        let next = trace.record(path);
+        if next.is_empty() {
+            return;
+        }
+        debug!("[TRACE] {:?} predicts {} files", path, next.len());
+        self.prefetcher.predict_files(next, self.head);
+    }
+
+    // Listing and sizes first so stat is served before content arrives. Only the
+    // directory's own files are urgent; the descent below it is speculative
+    fn prefetch_directory(&self, dir_path: &Path, priority: Priority) {
+        let depth = self.config.prefetch_depth;
+        self.prefetcher.prefetch_metadata(dir_path.to_path_buf(), self.head, priority);
+        if depth == 0 || priority <= Priority::Normal {
+            self.prefetcher.prefetch_directory(dir_path.to_path_buf(), depth, self.head, priority);
+            return;
+        }
+        self.prefetcher.prefetch_directory(dir_path.to_path_buf(), 0, self.head, priority);
+        self.prefetcher.prefetch_directory(dir_path.to_path_buf(), depth, self.head, Priority::Normal);
+    }
+}
+
+fn count_tree_entries(repo: &Repository, head: git2::Oid) -> u64 {
+    let Ok(tree) = repo.find_commit(head).and_then(|c| c.tree()) else { return 0 };
+    let mut count = 0;
+    let _ = tree.walk(git2::TreeWalkMode::PreOrder, |_, _| {
+        count += 1;
+        git2::TreeWalkResult::Ok
+    });
+    count
+}
+
+fn new_entry(kind: FileType, req: &Request<'_>, mode: u32, umask: u32) -> OverlayEntry {
+    let mut entry = OverlayEntry::new(kind);
+    entry.perm = Some((mode & !umask & 0o7777) as u16);
+    entry.uid = Some(req.uid());
+    entry.gid = Some(req.gid());
+    entry
+}
+
+fn caller(req: &Request<'_>) -> access::Caller {
+    access::Caller::new(req.uid(), req.gid(), req.pid())
+}
+
+fn time_or_now(time: TimeOrNow, now: SystemTime) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(t) => t,
+        TimeOrNow::Now => now,
     }
 }
 
 impl Filesystem for GitFsOverlay {
-    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, lock_owner: u64, reply: ReplyEmpty) {
+        let _timer = self.begin();
+        // The owner's file is being closed, so a lock it is blocked on will never be used
+        self.locks.cancel_waits(ino, lock_owner);
         reply.ok();
     }
 
-    fn init(&mut self, _: &Request<'_>, _: &mut KernelConfig) -> Result<(), libc::c_int> {
+    fn init(&mut self, _: &Request<'_>, config: &mut KernelConfig) -> Result<(), libc::c_int> {
         debug!("GitFS Overlay mounted");
+        self.prefetcher.warm_profile(self.head);
+        self.prefetcher.warm_history(self.head, self.config.prefetch_history);
+        if self.config.head_poll_ms > 0 {
+            head_watch::watch_head(
+                self.repo_path.clone(),
+                Duration::from_millis(self.config.head_poll_ms),
+                self.head,
+                self.pending_head.clone(),
+                self.node_cache.clone(),
+                self.invalidator.clone(),
+            );
+        }
+        let control = Control {
+            repo_path: self.repo_path.clone(),
+            prefetcher: self.prefetcher.clone(),
+            overlay: self.overlay.clone(),
+            head: self.served_head.clone(),
+            pending_head: self.pending_head.clone(),
+            node_cache: self.node_cache.clone(),
+            invalidator: self.invalidator.clone(),
+        };
+        match control.serve(&self.repo.path().join(CONTROL_SOCKET)) {
+            Ok(socket) => self.control = Some(socket),
+            Err(e) => debug!("[INIT] control socket unavailable: {}", e),
+        }
+        // Without these the kernel keeps locks local to this machine's view of the mount
+        if let Err(missing) = config.add_capabilities(consts::FUSE_POSIX_LOCKS | consts::FUSE_FLOCK_LOCKS) {
+            debug!("[INIT] kernel lacks lock capabilities {:#x}", missing);
+        }
+        // O_TRUNC arrives with the open instead of as a separate truncate
+        if let Err(missing) = config.add_capabilities(consts::FUSE_ATOMIC_O_TRUNC) {
+            debug!("[INIT] kernel lacks atomic O_TRUNC {:#x}", missing);
+        }
+        // Buffered writes are collected in the page cache and sent in large chunks
+        match config.add_capabilities(consts::FUSE_WRITEBACK_CACHE) {
+            Ok(()) => self.writeback = true,
+            Err(missing) => debug!("[INIT] kernel lacks writeback cache {:#x}", missing),
+        }
+        if let Err(nearest) = config.set_max_write(MAX_WRITE) {
+            let _ = config.set_max_write(nearest);
+        }
+        if let Err(nearest) = config.set_max_readahead(MAX_READAHEAD) {
+            let _ = config.set_max_readahead(nearest);
+        }
         Ok(())
     }
 
+    fn destroy(&mut self) {
+        // Flushes the access trace
+        self.trace = None;
+        self.control = None;
+        self.metrics.log();
+    }
+
     fn lookup(&mut self, _: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let _timer = self.begin();
         debug!("[LOOKUP] parent={}, name={:?}", parent, name);
         let parent_node = match self.node_cache.get_node(&parent) {
             Some(n) => n,
@@ -93,16 +379,16 @@ impl Filesystem for GitFsOverlay {
 
         let path = parent_node.path.join(name);
         debug!("[LOOKUP] looking up path: {:?}", path);
-        match self.node_cache.lookup_path(&path, &self.overlay, &self.repo, self.head) {
+        match self.node_cache.lookup_path(&path, &self.overlay_store, &self.repo, self.head) {
             Some(n) => {
                 debug!("[LOOKUP] found: {:?}, kind={:?}", path, n.kind);
                 
                 // If it's a directory, prefetch its contents
                 if n.kind == FileType::Directory {
-                    self.prefetch_directory(&n.path);
+                    self.prefetch_directory(&n.path, Priority::Normal);
                 }
                 
-                reply.entry(&TTL, &self.node_cache.node_to_attr(&n), 0)
+                reply.entry(&self.ttl(&n), &self.attr(&n), 0)
             },
             None => {
                 debug!("[LOOKUP] not found: {:?}", path);
@@ -111,9 +397,22 @@ impl Filesystem for GitFsOverlay {
         }
     }
 
+    fn forget(&mut self, _req: &Request<'_>, ino: u64, nlookup: u64) {
+        let _timer = self.begin();
+        self.node_cache.forget(ino, nlookup);
+    }
+
+    fn batch_forget(&mut self, _req: &Request<'_>, nodes: &[fuse_forget_one]) {
+        let _timer = self.begin();
+        for node in nodes {
+            self.node_cache.forget(node.nodeid, node.nlookup);
+        }
+    }
+
     fn getattr(&mut self, _: &Request<'_>, ino: u64, _: Option<u64>, reply: ReplyAttr) {
+        let _timer = self.begin();
         match self.node_cache.get_node(&ino) {
-            Some(n) => reply.attr(&TTL, &self.node_cache.node_to_attr(&n)),
+            Some(n) => reply.attr(&self.ttl(&n), &self.attr(&n)),
             None => reply.error(ENOENT),
         }
     }
@@ -126,6 +425,7 @@ impl Filesystem for GitFsOverlay {
         offset: i64,
         reply: ReplyDirectory,
     ) {
+        let _timer = self.begin();
         let node = match self.node_cache.get_node(&ino) {
             Some(n) => n,
             None => {
@@ -138,14 +438,14 @@ impl Filesystem for GitFsOverlay {
             &node,
             offset,
             &self.node_cache,
-            &self.overlay,
+            &self.overlay_store,
             &self.repo,
             self.head,
             reply,
         );
         
-        // Trigger prefetch for this directory
-        self.prefetch_directory(&node.path);
+        // Listing usually comes right before opening files, so this goes first
+        self.prefetch_directory(&node.path, Priority::High);
     }
 
     fn read(
@@ -159,6 +459,7 @@ impl Filesystem for GitFsOverlay {
         _: Option<u64>,
         reply: ReplyData,
     ) {
+        let _timer = self.begin();
         let node = match self.node_cache.get_node(&ino) {
             Some(n) => n,
             None => {
@@ -171,6 +472,7 @@ impl Filesystem for GitFsOverlay {
             &node,
             offset,
             size,
+            &self.overlay_store,
             &self.overlay,
             &self.repo,
             self.head,
@@ -183,20 +485,30 @@ impl Filesystem for GitFsOverlay {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: i64,
         data: &[u8],
-        _flags: u32,
-        _write_flags: i32,
+        _write_flags: u32,
+        _flags: i32,
         _lock_owner: Option<u64>,
         reply: ReplyWrite
     ) {
+        let _timer = self.begin();
+        // Access was checked at open; the handle only has to allow writing
+        if !self.writable_handles.contains(&fh) {
+            return reply.error(libc::EBADF);
+        }
+
+        if let Some(node) = self.node_cache.get_node(&ino) {
+            self.overlay_store.touch(&node.path);
+        }
+        
         file_ops::write_file(
             ino,
             offset,
             data,
             &self.node_cache,
-            &self.overlay,
+            &self.overlay_store,
             &self.repo,
             self.head,
             reply,
@@ -205,13 +517,14 @@ impl Filesystem for GitFsOverlay {
 
     fn mkdir(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
+        let _timer = self.begin();
         debug!("[MKDIR] parent={}, name={:?}", parent, name);
         let parent_node = match self.node_cache.get_node(&parent) {
             Some(n) => n,
@@ -220,36 +533,41 @@ impl Filesystem for GitFsOverlay {
                 return reply.error(ENOENT);
             }
         };
+        if let Err(e) = self.check(req, &parent_node, libc::W_OK | libc::X_OK) {
+            return reply.error(e);
+        }
 
         let path = parent_node.path.join(name);
         debug!("[MKDIR] creating directory: {:?}", path);
         let ino = self.node_cache.alloc_ino(&path);
         
-        // Mark directory in overlay as empty vec to make it visible
-        self.overlay.insert(path.clone(), Vec::new());
-        
+        let entry = new_entry(FileType::Directory, req, mode, umask);
         let node = Node {
             ino,
             kind: FileType::Directory,
             size: 0,
             path: path.clone(),
             git_mode: Some(FileMode::Tree),
+            mtime: entry.mtime,
+            nlookup: 1,
         };
         
+        self.overlay_store.insert(path, entry);
         self.node_cache.insert_node(ino, node.clone());
-        reply.entry(&TTL, &self.node_cache.node_to_attr(&node), 0);
+        reply.entry(&TTL, &self.attr(&node), 0);
     }
 
     fn create(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        _flags: i32,
+        mode: u32,
+        umask: u32,
+        flags: i32,
         reply: ReplyCreate,
     ) {
+        let _timer = self.begin();
         debug!("[CREATE] parent={}, name={:?}", parent, name);
         let parent_node = match self.node_cache.get_node(&parent) {
             Some(n) => n,
@@ -258,50 +576,189 @@ impl Filesystem for GitFsOverlay {
                 return reply.error(ENOENT);
             }
         };
+        if let Err(e) = self.check(req, &parent_node, libc::W_OK | libc::X_OK) {
+            return reply.error(e);
+        }
 
         let path = parent_node.path.join(name);
         debug!("[CREATE] creating file: {:?}", path);
         let ino = self.node_cache.alloc_ino(&path);
         
-        // Create empty file in overlay
-        self.overlay.insert(path.clone(), Vec::new());
+        let entry = new_entry(FileType::RegularFile, req, mode, umask);
         
         let node = Node {
             ino,
             kind: FileType::RegularFile,
             size: 0,
             path: path.clone(),
-            git_mode: Some(FileMode::Blob),
+            git_mode: entry.git_mode(),
+            mtime: entry.mtime,
+            nlookup: 1,
         };
         
+        // Create empty file in overlay
+        self.overlay_store.insert(path.clone(), entry);
+        if let Err(e) = self.overlay_store.replace_data(&path, &[]) {
+            debug!("[CREATE] failed to create backing file: {}", e);
+            self.overlay_store.remove(&path);
+            return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+        
+        self.node_cache.insert_node(ino, node.clone());
+        let open_flags = file_ops::open_flags(&node, flags, &self.overlay_store, &self.repo, self.head);
+        let fh = self.open_handle(ino, flags);
+        reply.created(&TTL, &self.attr(&node), 0, fh, open_flags);
+    }
+
+    fn mknod(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        let _timer = self.begin();
+        debug!("[MKNOD] parent={}, name={:?}, mode={:#o}", parent, name, mode);
+        let parent_node = match self.node_cache.get_node(&parent) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        if let Err(e) = self.check(req, &parent_node, libc::W_OK | libc::X_OK) {
+            return reply.error(e);
+        }
+
+        let Some(kind) = mknod_kind(mode) else {
+            return reply.error(libc::EINVAL);
+        };
+
+        let path = parent_node.path.join(name);
+        let ino = self.node_cache.alloc_ino(&path);
+
+        // Special files are metadata only; the kernel handles their I/O itself
+        let mut entry = new_entry(kind, req, mode, umask);
+        if matches!(kind, FileType::CharDevice | FileType::BlockDevice) {
+            entry.rdev = rdev;
+        }
+        let node = Node {
+            ino,
+            kind,
+            size: 0,
+            path: path.clone(),
+            git_mode: entry.git_mode(),
+            mtime: entry.mtime,
+            nlookup: 1,
+        };
+
+        self.overlay_store.insert(path.clone(), entry);
+        if kind == FileType::RegularFile
+            && let Err(e) = self.overlay_store.replace_data(&path, &[])
+        {
+            self.overlay_store.remove(&path);
+            return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+
         self.node_cache.insert_node(ino, node.clone());
-        reply.created(&TTL, &self.node_cache.node_to_attr(&node), 0, 0, 0);
+        reply.entry(&TTL, &self.attr(&node), 0);
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.begin();
         let parent_node = match self.node_cache.get_node(&parent) {
             Some(n) => n,
             None => return reply.error(ENOENT),
         };
 
         let path = parent_node.path.join(name);
+        if let Err(e) = self.check_remove(req, &parent_node, &path) {
+            return reply.error(e);
+        }
+        let survivors = self.overlay_store.links_of(&path);
+        let open_ino = self.node_cache
+            .get_ino_by_path(&path)
+            .filter(|ino| self.open_counts.contains_key(ino));
         
         // Remove from overlay
         self.overlay.remove(&path);
-        
-        // Remove from node cache
-        self.node_cache.remove_node(&path);
+        match (survivors.first(), open_ino) {
+            (Some(survivor), _) => {
+                self.overlay_store.remove(&path);
+                self.node_cache.remove_link(&path, survivor);
+            }
+            // Still open: the entry stays, out of sight, until the last release
+            (None, Some(ino)) if self.overlay_store.get(&path).is_some() => {
+                let orphan = self.overlay_store.orphan(&path, ino);
+                self.node_cache.remove_link(&path, &orphan);
+            }
+            (None, _) => {
+                self.overlay_store.remove(&path);
+                self.node_cache.remove_node(&path);
+            }
+        }
         
         reply.ok();
     }
 
-    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn link(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let _timer = self.begin();
+        debug!("[LINK] ino={}, newparent={}, newname={:?}", ino, newparent, newname);
+        let node = match self.node_cache.get_node(&ino) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        let newparent_node = match self.node_cache.get_node(&newparent) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        if node.kind != FileType::RegularFile {
+            return reply.error(libc::EPERM);
+        }
+
+        if let Err(e) = self.check(req, &newparent_node, libc::W_OK | libc::X_OK) {
+            return reply.error(e);
+        }
+
+        let new_path = newparent_node.path.join(newname);
+        if self.overlay_store.get(&new_path).is_some() || self.node_cache.get_ino_by_path(&new_path).is_some() {
+            return reply.error(libc::EEXIST);
+        }
+
+        // Both names share the backing file, so a tracked file is copied up first
+        let linked = file_ops::copy_up(&node.path, &self.overlay_store, &self.repo, self.head, false)
+            .and_then(|_| self.overlay_store.link(&node.path, &new_path));
+        if let Err(e) = linked {
+            return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+        }
+
+        match self.node_cache.add_link(ino, &new_path) {
+            Some(node) => reply.entry(&TTL, &self.attr(&node), 0),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.begin();
         let parent_node = match self.node_cache.get_node(&parent) {
             Some(n) => n,
             None => return reply.error(ENOENT),
         };
 
         let path = parent_node.path.join(name);
+        if let Err(e) = self.check_remove(req, &parent_node, &path) {
+            return reply.error(e);
+        }
+        
+        self.prefetcher.cancel(&path);
+        self.overlay_store.remove(&path);
         
         // Remove from node cache
         self.node_cache.remove_node(&path);
@@ -311,7 +768,7 @@ impl Filesystem for GitFsOverlay {
 
     fn rename(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         parent: u64,
         name: &OsStr,
         newparent: u64,
@@ -319,6 +776,7 @@ impl Filesystem for GitFsOverlay {
         _flags: u32,
         reply: ReplyEmpty,
     ) {
+        let _timer = self.begin();
         let parent_node = match self.node_cache.get_node(&parent) {
             Some(n) => n,
             None => return reply.error(ENOENT),
@@ -331,66 +789,222 @@ impl Filesystem for GitFsOverlay {
 
         let old_path = parent_node.path.join(name);
         let new_path = newparent_node.path.join(newname);
+        if let Err(e) = self.check_remove(req, &parent_node, &old_path)
+            .and_then(|_| self.check_remove(req, &newparent_node, &new_path))
+        {
+            return reply.error(e);
+        }
+
+        // Renaming a hard link onto another name of the same file does nothing
+        if self.overlay_store.links_of(&old_path).contains(&new_path) {
+            return reply.ok();
+        }
         
-        // Move in overlay if exists
-        if let Some(data) = self.overlay.remove(&old_path) {
-            self.overlay.insert(new_path.clone(), data);
+        // Content of a tracked file moves with it, so copy it up first
+        if let Some(ino) = self.node_cache.get_ino_by_path(&old_path)
+            && let Some(node) = self.node_cache.get_node(&ino)
+            && node.kind == FileType::RegularFile
+            && let Err(e) = file_ops::copy_up(&old_path, &self.overlay_store, &self.repo, self.head, false)
+        {
+            return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
         }
+        self.prefetcher.cancel(&old_path);
+        self.overlay_store.rename(&old_path, &new_path);
         
         // Update node cache
-        if let Some(ino) = self.node_cache.remove_node(&old_path) {
-            if let Some(mut node) = self.node_cache.get_node(&ino) {
-                node.path = new_path.clone();
-                self.node_cache.insert_node(ino, node);
-            }
-        }
+        self.node_cache.rename_node(&old_path, &new_path);
         
         reply.ok();
     }
 
     fn setattr(
         &mut self,
-        _req: &Request<'_>,
+        req: &Request<'_>,
         ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
-        _size: Option<u64>,
-        _atime: Option<fuser::TimeOrNow>,
-        _mtime: Option<fuser::TimeOrNow>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<fuser::TimeOrNow>,
+        mtime: Option<fuser::TimeOrNow>,
         _ctime: Option<SystemTime>,
-        _fh: Option<u64>,
+        fh: Option<u64>,
         _crtime: Option<SystemTime>,
         _chgtime: Option<SystemTime>,
         _bkuptime: Option<SystemTime>,
         _flags: Option<u32>,
         reply: ReplyAttr,
     ) {
-        debug!("[SETATTR] ino={}, size={:?}, mode={:?}", ino, _size, _mode);
+        let _timer = self.begin();
+        debug!("[SETATTR] ino={}, size={:?}, mode={:?}", ino, size, mode);
+        let mut node = match self.node_cache.get_node(&ino) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+
+        let explicit_times = matches!(atime, Some(TimeOrNow::SpecificTime(_)))
+            || matches!(mtime, Some(TimeOrNow::SpecificTime(_)));
+        let mut checked = Ok(());
+        if mode.is_some() || uid.is_some() || gid.is_some() || explicit_times {
+            checked = self.check_owner(req, &node);
+        } else if atime.is_some() || mtime.is_some() {
+            // Setting times to now is also open to anyone who may write the file
+            checked = self.check_owner(req, &node).or_else(|_| self.check(req, &node, libc::W_OK));
+        }
+        // Giving a file away takes root
+        if self.check_access && req.uid() != 0 && uid.is_some_and(|u| u != self.attr(&node).uid) {
+            checked = Err(libc::EPERM);
+        }
+        // and moving it to another group takes being a member of that group
+        if self.check_access
+            && req.uid() != 0
+            && gid.is_some_and(|g| g != self.attr(&node).gid && !caller(req).in_group(g))
+        {
+            checked = Err(libc::EPERM);
+        }
+        // ftruncate was checked when the handle was opened
+        if size.is_some() && fh.is_none() {
+            checked = checked.and_then(|_| self.check(req, &node, libc::W_OK));
+        }
+        if let Err(e) = checked {
+            return reply.error(e);
+        }
         
         // Handle size changes for truncate
-        if let Some(size) = _size {
+        if let Some(size) = size {
             debug!("[SETATTR] truncating to size {}", size);
-            if let Some(node) = self.node_cache.get_node(&ino) {
-                let mut content = self.overlay.get(&node.path).unwrap_or_else(Vec::new);
-                content.resize(size as usize, 0);
-                self.overlay.insert(node.path.clone(), content);
+            if let Err(e) = file_ops::truncate_overlay(ino, size, &self.node_cache, &self.overlay_store, &self.repo, self.head) {
+                return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+            self.overlay_store.touch(&node.path);
+            node.size = size;
+        }
+        
+        // chmod, chown and utimens are kept in the overlay entry
+        if mode.is_some() || uid.is_some() || gid.is_some() || atime.is_some() || mtime.is_some() {
+            debug!("[SETATTR] uid={:?}, gid={:?}, atime={:?}, mtime={:?}", uid, gid, atime, mtime);
+            let now = SystemTime::now();
+            let perm = mode.map(|m| (m & 0o7777) as u16);
+            let change = AttrChange {
+                perm,
+                uid,
+                gid,
+                atime: atime.map(|t| time_or_now(t, now)),
+                mtime: mtime.map(|t| time_or_now(t, now)),
+            };
+            let entry = self.overlay_store.set_attrs(&node.path, node.kind, &change);
+
+            // Executable bit maps back to the git file mode
+            if let Some(git_mode) = entry.git_mode() {
+                node.git_mode = Some(git_mode);
+                self.node_cache.insert_node(ino, node.clone());
             }
         }
         
-        // Return current attributes
+        reply.attr(&TTL, &self.attr(&node))
+    }
+
+    fn getxattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let _timer = self.begin();
+        let node = match self.node_cache.get_node(&ino) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        
+        xattr_ops::get_xattr(
+            &node,
+            name,
+            size,
+            &self.overlay_store,
+            &self.repo,
+            self.head,
+            reply,
+        );
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let _timer = self.begin();
+        let node = match self.node_cache.get_node(&ino) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        
+        xattr_ops::list_xattr(&node, size, &self.overlay_store, &self.repo, self.head, reply);
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.begin();
+        let node = match self.node_cache.get_node(&ino) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        
+        xattr_ops::set_xattr(&node, name, value, flags, &self.overlay_store, reply);
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let _timer = self.begin();
+        let node = match self.node_cache.get_node(&ino) {
+            Some(n) => n,
+            None => return reply.error(ENOENT),
+        };
+        
+        xattr_ops::remove_xattr(&node, name, &self.overlay_store, reply);
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        let _timer = self.begin();
         match self.node_cache.get_node(&ino) {
-            Some(n) => reply.attr(&TTL, &self.node_cache.node_to_attr(&n)),
+            Some(n) => match self.check(req, &n, mask) {
+                Ok(()) => reply.ok(),
+                Err(e) => reply.error(e),
+            },
             None => reply.error(ENOENT),
         }
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let _timer = self.begin();
         debug!("[OPEN] ino={}, flags={:#x}", ino, flags);
         match self.node_cache.get_node(&ino) {
             Some(n) => {
+                if let Err(e) = self.check(req, &n, access::open_mask(flags, self.writeback)) {
+                    return reply.error(e);
+                }
                 debug!("[OPEN] opened: {:?}", n.path);
-                reply.opened(0, flags as u32)
+                
+                // Copy up before the first write so it never sees a partial file; with
+                // O_TRUNC (atomic_o_trunc) the copy starts out empty
+                let writable = flags & libc::O_ACCMODE != libc::O_RDONLY;
+                if writable && n.kind == FileType::RegularFile {
+                    let copied = if flags & libc::O_TRUNC != 0 {
+                        file_ops::truncate_overlay(ino, 0, &self.node_cache, &self.overlay_store, &self.repo, self.head)
+                            .map(|_| self.overlay_store.touch(&n.path))
+                    } else {
+                        file_ops::copy_up(&n.path, &self.overlay_store, &self.repo, self.head, false)
+                    };
+                    if let Err(e) = copied {
+                        return reply.error(e.raw_os_error().unwrap_or(libc::EIO));
+                    }
+                }
+                
+                let open_flags = match n.kind {
+                    FileType::RegularFile => {
+                        self.predict_after(&n.path);
+                        file_ops::open_flags(&n, flags, &self.overlay_store, &self.repo, self.head)
+                    }
+                    _ => 0,
+                };
+                reply.opened(self.open_handle(ino, flags), open_flags)
             }
             None => {
                 debug!("[OPEN] inode not found");
@@ -399,20 +1013,218 @@ impl Filesystem for GitFsOverlay {
         }
     }
 
-    fn release(
+    fn lseek(&mut self, _req: &Request<'_>, ino: u64, _fh: u64, offset: i64, whence: i32, reply: ReplyLseek) {
+        let _timer = self.begin();
+        match self.node_cache.get_node(&ino) {
+            Some(n) => file_ops::seek(&n, offset, whence, &self.overlay_store, reply),
+            None => reply.error(ENOENT),
+        }
+    }
+
+    fn fallocate(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        length: i64,
+        mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.begin();
+        match file_ops::allocate(ino, offset, length, mode, &self.node_cache, &self.overlay_store, &self.repo, self.head) {
+            Ok(_) => {
+                if let Some(node) = self.node_cache.get_node(&ino) {
+                    self.overlay_store.touch(&node.path);
+                }
+                reply.ok()
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn copy_file_range(
+        &mut self,
+        _req: &Request<'_>,
+        ino_in: u64,
+        _fh_in: u64,
+        offset_in: i64,
+        ino_out: u64,
+        _fh_out: u64,
+        offset_out: i64,
+        len: u64,
+        flags: u32,
+        reply: ReplyWrite,
+    ) {
+        let _timer = self.begin();
+        if flags != 0 || offset_in < 0 || offset_out < 0 {
+            return reply.error(libc::EINVAL);
+        }
+
+        match file_ops::copy_range(
+            ino_in,
+            offset_in as u64,
+            ino_out,
+            offset_out as u64,
+            len,
+            &self.node_cache,
+            &self.overlay_store,
+            &self.repo,
+            self.head,
+        ) {
+            Ok(copied) => {
+                if let Some(node) = self.node_cache.get_node(&ino_out) {
+                    self.overlay_store.touch(&node.path);
+                }
+                reply.written(copied as u32)
+            }
+            Err(e) => reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        }
+    }
+
+    fn getlk(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        reply: ReplyLock,
+    ) {
+        let _timer = self.begin();
+        let lock = Lock { owner: lock_owner, start, end, typ, pid };
+        match self.locks.conflicting(ino, &lock) {
+            Some(l) => reply.locked(l.start, l.end, l.typ, l.pid),
+            None => reply.locked(start, end, libc::F_UNLCK, 0),
+        }
+    }
+
+    fn setlk(
         &mut self,
         _req: &Request<'_>,
-        _ino: u64,
+        ino: u64,
         _fh: u64,
+        lock_owner: u64,
+        start: u64,
+        end: u64,
+        typ: i32,
+        pid: u32,
+        sleep: bool,
+        reply: ReplyEmpty,
+    ) {
+        let _timer = self.begin();
+        let lock = Lock { owner: lock_owner, start, end, typ, pid };
+        if !matches!(typ, libc::F_RDLCK | libc::F_WRLCK | libc::F_UNLCK) {
+            return reply.error(libc::EINVAL);
+        }
+
+        match self.locks.try_lock(ino, lock) {
+            Ok(()) => reply.ok(),
+            // Wait on another thread so the unlock that frees it can still be served
+            Err(libc::EAGAIN) if sleep => {
+                let locks = self.locks.clone();
+                std::thread::spawn(move || match locks.lock_wait(ino, lock) {
+                    Ok(()) => reply.ok(),
+                    Err(e) => reply.error(e),
+                });
+            }
+            Err(e) => reply.error(e),
+        }
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
         _flags: i32,
-        _lock_owner: Option<u64>,
+        lock_owner: Option<u64>,
         _flush: bool,
         reply: ReplyEmpty,
     ) {
+        let _timer = self.begin();
+        self.writable_handles.remove(&fh);
+        if let Some(count) = self.open_counts.get_mut(&ino) {
+            *count -= 1;
+            if *count == 0 {
+                self.open_counts.remove(&ino);
+                if let Some(node) = self.node_cache.get_node(&ino)
+                    && OverlayStore::is_orphan(&node.path)
+                {
+                    self.overlay_store.remove(&node.path);
+                    self.node_cache.remove_node(&node.path);
+                }
+            }
+        }
+        // Set when the file still holds flock locks
+        if let Some(owner) = lock_owner {
+            self.locks.release_owner(ino, owner);
+        }
         reply.ok();
     }
 
+    fn statfs(&mut self, _req: &Request<'_>, _ino: u64, reply: ReplyStatfs) {
+        let _timer = self.begin();
+        let stats = match self.overlay_store.backing_stats() {
+            Ok(s) => s,
+            Err(e) => return reply.error(e.raw_os_error().unwrap_or(libc::EIO)),
+        };
+
+        // Capacity is what the mount holds (overlay plus blob cache) and what is
+        // still free where the overlay's backing files live
+        let frsize = stats.f_frsize.max(1);
+        let used = self.overlay_store.used_bytes() + self.overlay.stats().total_bytes as u64;
+        let used_blocks = used.div_ceil(frsize);
+        let files = *self.head_entries.get_or_init(|| count_tree_entries(&self.repo, self.head))
+            + self.overlay_store.entry_count() as u64;
+        debug!("[STATFS] used={} bytes, free={} blocks, files={}", used, stats.f_bavail, files);
+
+        reply.statfs(
+            used_blocks + stats.f_bfree,
+            stats.f_bfree,
+            stats.f_bavail,
+            files,
+            stats.f_ffree,
+            stats.f_bsize as u32,
+            255,
+            frsize as u32,
+        );
+    }
+
     fn fsync(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        let _timer = self.begin();
         reply.ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+
+    #[test]
+    fn counts_every_entry_in_the_head_tree() {
+        let dir = std::env::temp_dir().join(format!("gitfs-statfs-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let head = {
+            let mut src = repo.treebuilder(None).unwrap();
+            src.insert("a.rs", repo.blob(b"a").unwrap(), FileMode::Blob.into()).unwrap();
+            src.insert("b.rs", repo.blob(b"b").unwrap(), FileMode::Blob.into()).unwrap();
+            let src = src.write().unwrap();
+            let mut root = repo.treebuilder(None).unwrap();
+            root.insert("src", src, FileMode::Tree.into()).unwrap();
+            root.insert("README", repo.blob(b"r").unwrap(), FileMode::Blob.into()).unwrap();
+            let tree = repo.find_tree(root.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+        };
+
+        assert_eq!(count_tree_entries(&repo, head), 4);
+        assert_eq!(count_tree_entries(&repo, git2::Oid::zero()), 0);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,52 @@
+use git2::{Oid, Repository};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use crate::invalidate::Invalidator;
+use crate::metrics::debug;
+use crate::node_cache::NodeCache;
+use crate::types::ROOT_INO;
+
+/// Poll HEAD every `interval`. When it moves (checkout, commit, reset), the new
+/// commit is left in `pending` for the filesystem to switch to on its next
+/// request, and everything the kernel caches is invalidated so that request
+/// comes; the switch invalidates again.
+pub fn watch_head(
+    repo_path: PathBuf,
+    interval: Duration,
+    mut current: Oid,
+    pending: Arc<Mutex<Option<Oid>>>,
+    node_cache: Arc<NodeCache>,
+    invalidator: Invalidator,
+) {
+    thread::spawn(move || {
+        let Ok(repo) = Repository::open(&repo_path) else { return; };
+
+        loop {
+            thread::sleep(interval);
+            let Some(head) = repo.head().ok().and_then(|h| h.target()) else { continue; };
+            if head == current {
+                continue;
+            }
+
+            debug!("[HEAD] moved {} -> {}", current, head);
+            current = head;
+            *pending.lock().unwrap() = Some(head);
+            invalidate_all(&node_cache, &invalidator);
+        }
+    });
+}
+
+/// Drop everything the kernel caches about the mount. The watcher does this to
+/// make the next request come, and the switch does it again once applied, as
+/// requests in between refill the caches from the old HEAD.
+pub fn invalidate_all(node_cache: &NodeCache, invalidator: &Invalidator) {
+    invalidator.inode(ROOT_INO);
+    for (ino, parent, name) in node_cache.live_entries() {
+        invalidator.inode(ino);
+        if let Some(parent) = parent {
+            invalidator.entry(parent, name);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use fuser::Notifier;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, OnceLock, Weak};
+use std::thread;
+use crate::metrics::debug;
+use crate::node_cache::NodeCache;
+
+enum Invalidation {
+    Entry { parent: u64, name: OsString },
+    Inode { ino: u64 },
+}
+
+/// Channel for telling the kernel to drop cached dentries, attributes and pages.
+/// Notifications are sent from a thread of their own: issuing one from inside a
+/// request handler can deadlock against the kernel holding the inode's lock.
+#[derive(Clone)]
+pub struct Invalidator {
+    tx: Sender<Invalidation>,
+}
+
+impl Invalidator {
+    /// Notifications are dropped until `notifier` is set, which happens once the
+    /// session exists; nothing can be cached before then.
+    pub fn spawn(notifier: Arc<OnceLock<Notifier>>) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for inval in rx {
+                let Some(notifier) = notifier.get() else { continue };
+                // ENOENT only means the kernel had nothing cached
+                let result = match &inval {
+                    Invalidation::Entry { parent, name } => notifier.inval_entry(*parent, name),
+                    Invalidation::Inode { ino } => notifier.inval_inode(*ino, 0, 0),
+                };
+                if let Err(e) = result
+                    && e.raw_os_error() != Some(libc::ENOENT)
+                {
+                    debug!("[INVALIDATE] failed: {}", e);
+                }
+            }
+        });
+        Self { tx }
+    }
+
+    /// Forget the dentry `name` in directory `parent`, forcing a new lookup.
+    pub fn entry(&self, parent: u64, name: OsString) {
+        let _ = self.tx.send(Invalidation::Entry { parent, name });
+    }
+
+    /// Drop the attributes and cached pages of `ino`.
+    pub fn inode(&self, ino: u64) {
+        let _ = self.tx.send(Invalidation::Inode { ino });
+    }
+
+    /// Drop what the kernel caches for `path`: its dentry, attributes and pages.
+    /// False when the kernel holds no inode for it.
+    pub fn path(&self, node_cache: &NodeCache, path: &Path) -> bool {
+        let parent = path.parent().and_then(|p| node_cache.get_ino_by_path(p));
+        if let (Some(parent), Some(name)) = (parent, path.file_name()) {
+            self.entry(parent, name.to_os_string());
+        }
+        let Some(ino) = node_cache.get_ino_by_path(path) else { return false };
+        self.inode(ino);
+        true
+    }
+
+    /// Drop the attributes of each path received on `paths` that the kernel
+    /// holds an inode for, until every sender is gone or the cache is.
+    pub fn paths(&self, paths: Receiver<PathBuf>, node_cache: Weak<NodeCache>) {
+        let invalidator = self.clone();
+        thread::spawn(move || {
+            for path in paths {
+                let Some(node_cache) = node_cache.upgrade() else { return; };
+                if let Some(ino) = node_cache.get_ino_by_path(&path) {
+                    invalidator.inode(ino);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commit_times::CommitTimes;
+    use crate::overlay_store::OverlayStore;
+    use git2::{FileMode, Repository, Signature};
+
+    #[test]
+    fn paths_drop_their_dentry_and_inode() {
+        let dir = std::env::temp_dir().join(format!("gitfs-inval-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let head = {
+            let mut src = repo.treebuilder(None).unwrap();
+            src.insert("lib.rs", repo.blob(b"fn main() {}").unwrap(), FileMode::Blob.into()).unwrap();
+            let src = src.write().unwrap();
+            let mut root = repo.treebuilder(None).unwrap();
+            root.insert("src", src, FileMode::Tree.into()).unwrap();
+            let tree = repo.find_tree(root.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+        };
+        let store = OverlayStore::new(dir.join("overlay")).unwrap();
+        let cache = NodeCache::new(CommitTimes::new(&repo, head, None).unwrap());
+        let (tx, rx) = mpsc::channel();
+        let invalidator = Invalidator { tx };
+
+        let src = cache.lookup_path(Path::new("src"), &store, &repo, head).unwrap().ino;
+        let lib = cache.lookup_path(Path::new("src/lib.rs"), &store, &repo, head).unwrap().ino;
+        assert!(invalidator.path(&cache, Path::new("src/lib.rs")));
+        assert!(matches!(rx.try_recv(), Ok(Invalidation::Entry { parent, name }) if parent == src && name == "lib.rs"));
+        assert!(matches!(rx.try_recv(), Ok(Invalidation::Inode { ino }) if ino == lib));
+
+        // A path the kernel never looked up still loses a negative dentry
+        assert!(!invalidator.path(&cache, Path::new("src/new.rs")));
+        assert!(matches!(rx.try_recv(), Ok(Invalidation::Entry { parent, .. }) if parent == src));
+        assert!(rx.try_recv().is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use crate::metrics::debug;
+
+#[derive(Clone, Copy)]
+pub struct Lock {
+    pub owner: u64,
+    pub start: u64,
+    // Inclusive; whole-file locks run to OFFSET_MAX
+    pub end: u64,
+    pub typ: i32,
+    pub pid: u32,
+}
+
+impl Lock {
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts_with(&self, other: &Lock) -> bool {
+        self.owner != other.owner
+            && self.overlaps(other.start, other.end)
+            && (self.typ == libc::F_WRLCK || other.typ == libc::F_WRLCK)
+    }
+}
+
+/// Advisory locks taken through the mount, per inode and lock owner. POSIX
+/// byte-range locks and flock locks share the table; the kernel sends flock as
+/// a lock over the whole file owned by the open file, so both conflict the same way.
+#[derive(Default)]
+pub struct LockTable {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    locks: HashMap<u64, Vec<Lock>>,
+    // Blocked F_SETLKW requests by ticket, with their inode; dropping one cancels the wait
+    waiting: HashMap<u64, (u64, Lock)>,
+    next_ticket: u64,
+}
+
+impl LockTable {
+    /// First lock of another owner standing in the way of `lock` on `ino`.
+    pub fn conflicting(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        let state = self.state.lock().unwrap();
+        state.find_conflict(ino, lock)
+    }
+
+    /// F_SETLK: take `lock`, or drop the range for F_UNLCK. Fails with EAGAIN
+    /// while another owner holds a conflicting lock.
+    pub fn try_lock(&self, ino: u64, lock: Lock) -> Result<(), libc::c_int> {
+        let mut state = self.state.lock().unwrap();
+        if lock.typ != libc::F_UNLCK && state.find_conflict(ino, &lock).is_some() {
+            return Err(libc::EAGAIN);
+        }
+        self.apply(&mut state, ino, lock);
+        Ok(())
+    }
+
+    /// F_SETLKW: wait until no other owner holds a conflicting lock, then take
+    /// it. Fails with EDEADLK when the holder is itself waiting on this owner,
+    /// and with EINTR when the wait is cancelled because the file is closed.
+    pub fn lock_wait(&self, ino: u64, lock: Lock) -> Result<(), libc::c_int> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+
+        while lock.typ != libc::F_UNLCK
+            && let Some(holder) = state.find_conflict(ino, &lock)
+        {
+            if state.waits_on(holder.owner, lock.owner) {
+                state.waiting.remove(&ticket);
+                return Err(libc::EDEADLK);
+            }
+            state.waiting.insert(ticket, (ino, lock));
+            state = self.released.wait(state).unwrap();
+            if !state.waiting.contains_key(&ticket) {
+                debug!("[LOCK] wait of owner {:#x} on ino={} cancelled", lock.owner, ino);
+                return Err(libc::EINTR);
+            }
+        }
+        state.waiting.remove(&ticket);
+        self.apply(&mut state, ino, lock);
+        Ok(())
+    }
+
+    /// Drop every lock `owner` holds on `ino`, and any wait it has pending there.
+    pub fn release_owner(&self, ino: u64, owner: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.cancel_waits(ino, owner);
+        if let Some(held) = state.locks.get_mut(&ino) {
+            held.retain(|l| l.owner != owner);
+            if held.is_empty() {
+                state.locks.remove(&ino);
+            }
+        }
+        self.released.notify_all();
+    }
+
+    /// Give up any wait `owner` has pending on `ino`; its file is being closed.
+    pub fn cancel_waits(&self, ino: u64, owner: u64) {
+        let mut state = self.state.lock().unwrap();
+        if state.cancel_waits(ino, owner) {
+            self.released.notify_all();
+        }
+    }
+
+    // The owner's locks in the range are replaced, splitting any that stick out on either side
+    fn apply(&self, state: &mut LockState, ino: u64, lock: Lock) {
+        let locks = &mut state.locks;
+        debug!("[LOCK] ino={}, owner={:#x}, range={}..={}, type={}", ino, lock.owner, lock.start, lock.end, lock.typ);
+        let held = locks.entry(ino).or_default();
+        let mut kept = Vec::with_capacity(held.len() + 2);
+        for l in held.drain(..) {
+            if l.owner != lock.owner || !l.overlaps(lock.start, lock.end) {
+                kept.push(l);
+                continue;
+            }
+            if l.start < lock.start {
+                kept.push(Lock { end: lock.start - 1, ..l });
+            }
+            if l.end > lock.end {
+                kept.push(Lock { start: lock.end + 1, ..l });
+            }
+        }
+        if lock.typ != libc::F_UNLCK {
+            kept.push(lock);
+        }
+
+        if kept.is_empty() {
+            locks.remove(&ino);
+        } else {
+            *held = kept;
+        }
+        self.released.notify_all();
+    }
+}
+
+impl LockState {
+    fn find_conflict(&self, ino: u64, lock: &Lock) -> Option<Lock> {
+        self.locks.get(&ino)?.iter().find(|l| l.conflicts_with(lock)).copied()
+    }
+
+    // Whether `owner` is blocked on a lock of `target`, directly or through a
+    // chain of other waiting owners
+    fn waits_on(&self, mut owner: u64, target: u64) -> bool {
+        for _ in 0..=self.waiting.len() {
+            let holder = self.waiting
+                .values()
+                .filter(|(_, l)| l.owner == owner)
+                .find_map(|(ino, l)| self.find_conflict(*ino, l));
+            match holder {
+                Some(h) if h.owner == target => return true,
+                Some(h) => owner = h.owner,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    fn cancel_waits(&mut self, ino: u64, owner: u64) -> bool {
+        let before = self.waiting.len();
+        self.waiting.retain(|_, (i, l)| *i != ino || l.owner != owner);
+        self.waiting.len() != before
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn write_lock(owner: u64, start: u64, end: u64) -> Lock {
+        Lock { owner, start, end, typ: libc::F_WRLCK, pid: owner as u32 }
+    }
+
+    #[test]
+    fn try_lock_conflicts_and_release_owner_frees() {
+        let table = LockTable::default();
+        table.try_lock(1, write_lock(1, 0, 9)).unwrap();
+        assert_eq!(table.try_lock(1, write_lock(2, 5, 20)), Err(libc::EAGAIN));
+        table.try_lock(1, write_lock(2, 10, 20)).unwrap();
+        table.try_lock(2, write_lock(2, 0, 9)).unwrap();
+
+        table.release_owner(1, 1);
+        table.try_lock(1, write_lock(2, 0, 9)).unwrap();
+    }
+
+    #[test]
+    fn lock_wait_returns_once_the_holder_releases() {
+        let table = Arc::new(LockTable::default());
+        table.try_lock(1, write_lock(1, 0, 9)).unwrap();
+        let waiter = {
+            let table = table.clone();
+            thread::spawn(move || table.lock_wait(1, write_lock(2, 0, 9)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        table.release_owner(1, 1);
+        assert_eq!(waiter.join().unwrap(), Ok(()));
+        assert!(table.conflicting(1, &write_lock(1, 0, 0)).is_some_and(|l| l.owner == 2));
+    }
+
+    #[test]
+    fn lock_wait_detects_deadlock_and_is_cancelled_on_close() {
+        let table = Arc::new(LockTable::default());
+        table.try_lock(1, write_lock(1, 0, 9)).unwrap();
+        table.try_lock(2, write_lock(2, 0, 9)).unwrap();
+        let waiter = {
+            let table = table.clone();
+            thread::spawn(move || table.lock_wait(2, write_lock(1, 0, 9)))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(table.lock_wait(1, write_lock(2, 0, 9)), Err(libc::EDEADLK));
+
+        // Closing the file cancels the blocked request
+        table.cancel_waits(2, 1);
+        assert_eq!(waiter.join().unwrap(), Err(libc::EINTR));
+    }
+}
@@ -1,20 +1,46 @@
 mod types;
+mod config;
 mod metrics;
 mod cache;
+mod commit_times;
 mod node_cache;
+mod overlay_store;
 mod prefetch;
 mod file_ops;
 mod dir_ops;
+mod xattr_ops;
+mod locks;
+mod access;
+mod invalidate;
+mod head_watch;
+mod trace;
+mod profile;
+mod control;
+mod tree_meta;
+mod pack_order;
 mod gitfs;
 
 use anyhow::{Context, Result};
-use fuser::MountOption;
+use fuser::{MountOption, Session};
+use config::Config;
 use gitfs::GitFsOverlay;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const WARMUP_USAGE: &str =
+    "usage: git_fuse_overlay warmup <repo> [--timeout <secs>] [--root <mountpoint>] [--all] [<list file>]";
+const DEFAULT_WARMUP_TIMEOUT_SECS: u64 = 600;
+const INVALIDATE_USAGE: &str = "usage: git_fuse_overlay invalidate <repo> [--root <mountpoint>] <path>...";
 
 fn main() -> Result<()> {
+    match std::env::args().nth(1).as_deref() {
+        Some("warmup") => return warmup(),
+        Some("invalidate") => return invalidate(),
+        _ => {}
+    }
+
     let repo = std::env::args()
         .nth(1)
         .context("usage: git_fuse_overlay <repo> <mountpoint>")?;
@@ -50,18 +76,77 @@ fn main() -> Result<()> {
     eprintln!("Mounting {} at {}", repo, mountpoint);
     let fs = GitFsOverlay::new(Path::new(&repo))?;
     
+    let mut options = vec![
+        MountOption::RO,
+        MountOption::FSName("sb_overlay".into()),
+        MountOption::AllowOther,
+        MountOption::CUSTOM("nonempty".into()),
+    ];
+    if Config::from_env().default_permissions {
+        options.push(MountOption::DefaultPermissions);
+    }
+
+    let notifier = fs.notifier_slot();
+    let mut session = Session::new(fs, &mountpoint, &options)?;
+    let _ = notifier.set(session.notifier());
+
     // This blocks until the filesystem is unmounted
-    fuser::mount2(
-        fs,
-        mountpoint,
-        &[
-            MountOption::RO,
-            MountOption::FSName("sb_overlay".into()),
-            MountOption::AllowOther,
-            MountOption::CUSTOM("nonempty".into()),
-        ],
-    )?;
+    session.run()?;
 
     eprintln!("Filesystem unmounted");
     Ok(())
 }
+
+// Preload the files listed in <list file> (stdin if omitted) into a running
+// mount, e.g. from a previous build's dependency files, and wait for it
+fn warmup() -> Result<()> {
+    let mut args = std::env::args().skip(2);
+    let repo = args.next().context(WARMUP_USAGE)?;
+    let mut timeout = Duration::from_secs(DEFAULT_WARMUP_TIMEOUT_SECS);
+    let mut root = None;
+    let mut all = false;
+    let mut list_file = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--timeout" => {
+                let secs = args.next().and_then(|s| s.parse().ok()).context(WARMUP_USAGE)?;
+                timeout = Duration::from_secs(secs);
+            }
+            "--root" => root = Some(PathBuf::from(args.next().context(WARMUP_USAGE)?)),
+            "--all" => all = true,
+            _ => list_file = Some(arg),
+        }
+    }
+
+    let list = match list_file {
+        Some(path) => std::fs::read_to_string(&path).with_context(|| format!("cannot read {}", path))?,
+        None => std::io::read_to_string(std::io::stdin())?,
+    };
+    let git_dir = git2::Repository::open(&repo)?.path().to_path_buf();
+    let socket = git_dir.join(control::CONTROL_SOCKET);
+    if !control::warmup(&socket, &list, root.as_deref(), timeout, all)? {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+// Tell a running mount that the overlay content of <path>s changed outside of
+// it, e.g. after a tool applied a patch to the backing files
+fn invalidate() -> Result<()> {
+    let mut args = std::env::args().skip(2);
+    let repo = args.next().context(INVALIDATE_USAGE)?;
+    let mut root = None;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--root" => root = Some(PathBuf::from(args.next().context(INVALIDATE_USAGE)?)),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        anyhow::bail!(INVALIDATE_USAGE);
+    }
+
+    let git_dir = git2::Repository::open(&repo)?.path().to_path_buf();
+    control::invalidate(&git_dir.join(control::CONTROL_SOCKET), &paths, root.as_deref())
+}
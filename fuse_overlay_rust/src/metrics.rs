@@ -16,6 +16,14 @@ pub struct Metrics {
     pub prefetch_bytes: AtomicU64,
     pub on_demand_count: AtomicU64,
     pub on_demand_bytes: AtomicU64,
+    // Files cached because the access trace predicted them, and reads they served
+    pub predicted_count: AtomicU64,
+    pub predicted_bytes: AtomicU64,
+    pub predicted_hits: AtomicU64,
+    // Files changed in recent commits, prefetched at mount, and reads they served
+    pub history_count: AtomicU64,
+    pub history_bytes: AtomicU64,
+    pub history_hits: AtomicU64,
 }
 
 impl Metrics {
@@ -24,14 +32,20 @@ impl Metrics {
         let prefetch_bytes = self.prefetch_bytes.load(Ordering::Relaxed);
         let on_demand_cnt = self.on_demand_count.load(Ordering::Relaxed);
         let on_demand_bytes = self.on_demand_bytes.load(Ordering::Relaxed);
+        let predicted_cnt = self.predicted_count.load(Ordering::Relaxed);
+        let predicted_bytes = self.predicted_bytes.load(Ordering::Relaxed);
+        let predicted_hits = self.predicted_hits.load(Ordering::Relaxed);
+        let history_cnt = self.history_count.load(Ordering::Relaxed);
+        let history_bytes = self.history_bytes.load(Ordering::Relaxed);
+        let history_hits = self.history_hits.load(Ordering::Relaxed);
         
         debug!("----- GitFS Metrics -----");
         debug!("Prefetch: {} files, {} bytes", prefetch_cnt, prefetch_bytes);
         debug!("On-demand: {} files, {} bytes", on_demand_cnt, on_demand_bytes);
+        debug!("Predicted: {} files, {} bytes, {} read", predicted_cnt, predicted_bytes, predicted_hits);
+        debug!("History: {} files, {} bytes, {} read", history_cnt, history_bytes, history_hits);
         
-        let total = prefetch_cnt + on_demand_cnt;
-        if total > 0 {
-            let prefetch_pct = (prefetch_cnt * 100) / total;
+        if let Some(prefetch_pct) = (prefetch_cnt * 100).checked_div(prefetch_cnt + on_demand_cnt) {
             debug!("Cache hit rate: {}%", prefetch_pct);
         }
     }
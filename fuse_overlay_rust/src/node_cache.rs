@@ -1,27 +1,37 @@
 use dashmap::DashMap;
 use fuser::{FileAttr, FileType};
 use git2::{ObjectType, Repository, FileMode};
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::SystemTime;
 use crate::types::{Node, ROOT_INO, i32_to_filemode, git_mode_to_perm};
-use crate::cache::LruCache;
+use crate::overlay_store::{OverlayEntry, OverlayStore};
+use crate::commit_times::CommitTimes;
+use crate::tree_meta::TreeMeta;
 
 pub struct NodeCache {
     nodes: DashMap<u64, Node>,
-    ino_cache: DashMap<PathBuf, u64>,
     path_to_ino: DashMap<PathBuf, u64>,
     next_ino: AtomicU64,
+    // Names of hard-linked inodes besides `Node.path`, unmapped along with the node
+    aliases: DashMap<u64, Vec<PathBuf>>,
+    // Replaced when HEAD moves
+    times: RwLock<CommitTimes>,
+    // Git tree listings and blob sizes, shared with the prefetcher
+    meta: Arc<TreeMeta>,
 }
 
 impl NodeCache {
-    pub fn new() -> Self {
+    pub fn new(times: CommitTimes) -> Self {
+        let root_mtime = times.head_time();
         let cache = Self {
             nodes: DashMap::new(),
-            ino_cache: DashMap::new(),
             path_to_ino: DashMap::new(),
             next_ino: AtomicU64::new(ROOT_INO + 1),
+            aliases: DashMap::new(),
+            times: RwLock::new(times),
+            meta: Arc::new(TreeMeta::default()),
         };
         
         // Insert root node
@@ -33,6 +43,8 @@ impl NodeCache {
                 size: 0,
                 path: PathBuf::new(),
                 git_mode: Some(FileMode::Tree),
+                mtime: root_mtime,
+                nlookup: 1,
             },
         );
         cache.path_to_ino.insert(PathBuf::new(), ROOT_INO);
@@ -41,30 +53,79 @@ impl NodeCache {
     }
 
     pub fn alloc_ino(&self, path: &Path) -> u64 {
-        if let Some(ino) = self.ino_cache.get(path) {
+        if let Some(ino) = self.path_to_ino.get(path) {
             *ino
         } else {
-            let ino = self.next_ino.fetch_add(1, Ordering::Relaxed);
-            self.ino_cache.insert(path.to_path_buf(), ino);
-            ino
+            self.next_ino.fetch_add(1, Ordering::Relaxed)
         }
     }
 
+    pub fn tree_meta(&self) -> &Arc<TreeMeta> {
+        &self.meta
+    }
+
     pub fn get_node(&self, ino: &u64) -> Option<Node> {
         self.nodes.get(ino).map(|n| n.clone())
     }
 
     pub fn insert_node(&self, ino: u64, node: Node) {
-        self.nodes.insert(ino, node.clone());
         self.path_to_ino.insert(node.path.clone(), ino);
+        self.nodes.insert(ino, node);
     }
 
+    /// Unlink a path. The node itself stays until the kernel forgets it,
+    /// since open handles may still refer to the inode.
     pub fn remove_node(&self, path: &Path) -> Option<u64> {
-        if let Some((_, ino)) = self.path_to_ino.remove(path) {
-            self.nodes.remove(&ino);
-            Some(ino)
-        } else {
-            None
+        let (_, ino) = self.path_to_ino.remove(path)?;
+        if let Some(mut aliases) = self.aliases.get_mut(&ino) {
+            aliases.retain(|p| p != path);
+        }
+        Some(ino)
+    }
+
+    /// Unlink one name of a hard-linked inode. A node known by that name moves
+    /// to `survivor` so open handles keep reaching the shared entry.
+    pub fn remove_link(&self, path: &Path, survivor: &Path) {
+        let Some(ino) = self.remove_node(path) else { return };
+        if let Some(mut node) = self.nodes.get_mut(&ino)
+            && node.path == path
+        {
+            node.path = survivor.to_path_buf();
+            if let Some(mut aliases) = self.aliases.get_mut(&ino) {
+                aliases.retain(|p| p != survivor);
+            }
+            self.path_to_ino.insert(survivor.to_path_buf(), ino);
+        }
+    }
+
+    /// Move one name of an inode to `new_path`.
+    pub fn rename_node(&self, old_path: &Path, new_path: &Path) {
+        let Some(ino) = self.remove_node(old_path) else { return };
+        match self.nodes.get_mut(&ino) {
+            Some(mut node) if node.path == old_path => node.path = new_path.to_path_buf(),
+            Some(_) => self.aliases.entry(ino).or_default().push(new_path.to_path_buf()),
+            None => return,
+        }
+        self.path_to_ino.insert(new_path.to_path_buf(), ino);
+    }
+
+    /// Give inode `ino` another name. The kernel counts the returned entry as a lookup.
+    pub fn add_link(&self, ino: u64, path: &Path) -> Option<Node> {
+        let node = {
+            let mut node = self.nodes.get_mut(&ino)?;
+            node.nlookup += 1;
+            node.clone()
+        };
+        if node.path != path {
+            self.path_to_ino.insert(path.to_path_buf(), ino);
+            self.aliases.entry(ino).or_default().push(path.to_path_buf());
+        }
+        Some(node)
+    }
+
+    pub fn set_size(&self, ino: u64, size: u64) {
+        if let Some(mut node) = self.nodes.get_mut(&ino) {
+            node.size = size;
         }
     }
 
@@ -72,10 +133,87 @@ impl NodeCache {
         self.path_to_ino.get(path).map(|i| *i)
     }
 
-    pub fn node_to_attr(&self, node: &Node) -> FileAttr {
-        let perm = match &node.git_mode {
-            Some(mode) => git_mode_to_perm(*mode),
-            None => match node.kind {
+    /// Drop `nlookup` references the kernel held on `ino`, freeing the node
+    /// once none are left.
+    pub fn forget(&self, ino: u64, nlookup: u64) {
+        if ino == ROOT_INO {
+            return;
+        }
+
+        if let Some(mut node) = self.nodes.get_mut(&ino) {
+            node.nlookup = node.nlookup.saturating_sub(nlookup);
+        }
+
+        if let Some((_, node)) = self.nodes.remove_if(&ino, |_, n| n.nlookup == 0) {
+            self.path_to_ino.remove_if(&node.path, |_, i| *i == ino);
+            for alias in self.aliases.remove(&ino).map(|(_, a)| a).unwrap_or_default() {
+                self.path_to_ino.remove_if(&alias, |_, i| *i == ino);
+            }
+        }
+    }
+
+    /// Live inodes other than the root, with the parent inode and name the kernel
+    /// knows them by (no parent when the parent has been forgotten).
+    pub fn live_entries(&self) -> Vec<(u64, Option<u64>, OsString)> {
+        self.nodes
+            .iter()
+            .filter(|n| n.ino != ROOT_INO)
+            .filter_map(|n| {
+                let name = n.path.file_name()?.to_os_string();
+                let parent = n.path.parent().and_then(|p| self.get_ino_by_path(p));
+                Some((n.ino, parent, name))
+            })
+            .collect()
+    }
+
+    /// Switch to a new HEAD: re-resolve every live node against it, keeping inode
+    /// numbers and lookup counts. Paths gone from both the new tree and the
+    /// overlay are unmapped so the next lookup fails.
+    pub fn switch_head(&self, times: CommitTimes, overlay_store: &OverlayStore, repo: &Repository, head: git2::Oid) {
+        if let Some(mut root) = self.nodes.get_mut(&ROOT_INO) {
+            root.mtime = times.head_time();
+        }
+        *self.times.write().unwrap() = times;
+
+        let live: Vec<(u64, PathBuf)> = self.nodes
+            .iter()
+            .filter(|n| n.ino != ROOT_INO)
+            .map(|n| (n.ino, n.path.clone()))
+            .collect();
+        for (ino, path) in live {
+            match self.resolve_path(&path, overlay_store, repo, head) {
+                Some(fresh) => {
+                    if let Some(mut node) = self.nodes.get_mut(&ino) {
+                        node.kind = fresh.kind;
+                        node.size = fresh.size;
+                        node.git_mode = fresh.git_mode;
+                        node.mtime = fresh.mtime;
+                    }
+                }
+                None => {
+                    self.remove_node(&path);
+                }
+            }
+        }
+    }
+
+    /// Whether the time reported for git content at `path` is a stand-in for
+    /// its per-path commit time, which is still being looked up.
+    pub fn time_pending(&self, path: &Path) -> bool {
+        self.times.read().unwrap().pending(path)
+    }
+
+    /// Attributes for `node`; times come from its overlay entry when it has one.
+    pub fn node_to_attr(&self, node: &Node, entry: Option<&OverlayEntry>) -> FileAttr {
+        // Per-path commit times arrive after the node was resolved
+        let git_time = match entry {
+            Some(_) => node.mtime,
+            None => self.times.read().unwrap().time_for(&node.path),
+        };
+        let perm = match (entry.and_then(|e| e.perm), &node.git_mode) {
+            (Some(perm), _) => perm,
+            (None, Some(mode)) => git_mode_to_perm(*mode),
+            (None, None) => match node.kind {
                 FileType::Directory => 0o755,
                 _ => 0o644,
             },
@@ -84,94 +222,201 @@ impl NodeCache {
         FileAttr {
             ino: node.ino,
             size: node.size,
-            blocks: (node.size + 511) / 512,
-            atime: SystemTime::now(),
-            mtime: SystemTime::now(),
-            ctime: SystemTime::now(),
-            crtime: SystemTime::now(),
+            blocks: node.size.div_ceil(512),
+            atime: entry.map_or(git_time, |e| e.atime),
+            mtime: entry.map_or(git_time, |e| e.mtime),
+            ctime: entry.map_or(git_time, |e| e.ctime),
+            crtime: git_time,
             kind: node.kind,
             perm,
-            nlink: 1,
-            uid: unsafe { libc::geteuid() },
-            gid: unsafe { libc::getegid() },
-            rdev: 0,
+            nlink: entry.map_or(1, |e| e.nlink),
+            uid: entry.and_then(|e| e.uid).unwrap_or_else(|| unsafe { libc::geteuid() }),
+            gid: entry.and_then(|e| e.gid).unwrap_or_else(|| unsafe { libc::getegid() }),
+            rdev: entry.map_or(0, |e| e.rdev),
             flags: 0,
             blksize: 512,
         }
     }
 
+    /// Resolve `path` to a node on behalf of a kernel lookup, bumping its lookup count.
     pub fn lookup_path(
         &self,
         path: &Path,
-        overlay: &Arc<LruCache>,
+        overlay_store: &OverlayStore,
         repo: &Repository,
         head: git2::Oid,
     ) -> Option<Node> {
-        // Check cached inode first (preserves directory type)
-        if let Some(ino) = self.path_to_ino.get(path) {
-            return self.nodes.get(&*ino).map(|n| n.clone());
+        // Live inode first (preserves the kind of overlay entries)
+        if let Some(ino) = self.get_ino_by_path(path)
+            && let Some(mut node) = self.nodes.get_mut(&ino)
+        {
+            node.nlookup += 1;
+            return Some(node.clone());
         }
 
-        // Check overlay (but only for files, directories are in nodes)
-        let path_buf = path.to_path_buf();
-        if let Some(data) = overlay.get(&path_buf) {
-            let ino = self.alloc_ino(path);
-            let node = Node {
-                ino,
-                kind: FileType::RegularFile,
-                size: data.len() as u64,
-                path: path_buf.clone(),
-                git_mode: None,
-            };
-            self.nodes.insert(ino, node.clone());
-            self.path_to_ino.insert(path_buf, ino);
-            return Some(node);
+        // Another name of a hard-linked file the kernel already holds
+        for other in overlay_store.links_of(path) {
+            if let Some(ino) = self.get_ino_by_path(&other)
+                && self.nodes.contains_key(&ino)
+            {
+                return self.add_link(ino, path);
+            }
         }
 
-        // Git traversal
-        let commit = repo.find_commit(head).ok()?;
-        let mut curr_tree = commit.tree().ok()?;
-        let mut curr_path = PathBuf::new();
-        let mut last_node: Option<Node> = None;
-
-        for comp in path.iter() {
-            let comp_str = comp.to_str()?;
-            curr_path.push(comp);
-
-            let tree_next = {
-                let entry = curr_tree.get_name(comp_str)?;
-                let kind = match entry.kind() {
-                    Some(ObjectType::Tree) => FileType::Directory,
-                    Some(ObjectType::Blob) => FileType::RegularFile,
-                    _ => return None,
-                };
-
-                let size = if kind == FileType::RegularFile {
-                    entry.to_object(repo).ok()?.peel_to_blob().ok()?.size() as u64
-                } else {
-                    0
-                };
-
-                let node = Node {
-                    ino: self.alloc_ino(&curr_path),
-                    kind,
-                    size,
-                    path: curr_path.clone(),
-                    git_mode: Some(i32_to_filemode(entry.filemode())),
-                };
-                self.nodes.insert(node.ino, node.clone());
-                self.path_to_ino.insert(curr_path.clone(), node.ino);
-                last_node = Some(node.clone());
-
-                if kind == FileType::Directory {
-                    entry.to_object(repo).ok()?.peel_to_tree().ok()?
-                } else {
-                    return last_node;
-                }
-            };
-            curr_tree = tree_next;
+        let node = self.resolve_path(path, overlay_store, repo, head)?;
+        self.insert_node(node.ino, node.clone());
+        Some(node)
+    }
+
+    fn resolve_path(
+        &self,
+        path: &Path,
+        overlay_store: &OverlayStore,
+        repo: &Repository,
+        head: git2::Oid,
+    ) -> Option<Node> {
+        let entry = overlay_store.get(path);
+        let base_oid = entry.as_ref().and_then(|e| e.base_oid);
+        let overlay_size = || {
+            overlay_store.data_size(path).or_else(|| {
+                self.meta.blob_size(repo, base_oid?)
+            })
+        };
+
+        if let Some(mut node) = self.resolve_git_path(path, repo, head) {
+            // Tracked file modified through the mount
+            if let Some(entry) = &entry
+                && node.kind == FileType::RegularFile
+            {
+                node.size = overlay_size().unwrap_or(node.size);
+                node.git_mode = entry.git_mode().or(node.git_mode);
+            }
+            return Some(node);
         }
 
-        last_node
+        // Entries created through the mount
+        let entry = entry?;
+        let size = match entry.kind {
+            FileType::RegularFile => overlay_size().unwrap_or(0),
+            _ => 0,
+        };
+        Some(Node {
+            ino: self.alloc_ino(path),
+            kind: entry.kind,
+            size,
+            path: path.to_path_buf(),
+            git_mode: entry.git_mode(),
+            mtime: entry.mtime,
+            nlookup: 1,
+        })
+    }
+
+    fn resolve_git_path(&self, path: &Path, repo: &Repository, head: git2::Oid) -> Option<Node> {
+        let entry = self.meta.entry_at(repo, head, path)?;
+        let (kind, size) = match entry.kind {
+            ObjectType::Tree => (FileType::Directory, 0),
+            ObjectType::Blob => (FileType::RegularFile, self.meta.blob_size(repo, entry.oid)?),
+            _ => return None,
+        };
+
+        Some(Node {
+            ino: self.alloc_ino(path),
+            kind,
+            size,
+            path: path.to_path_buf(),
+            git_mode: Some(i32_to_filemode(entry.mode)),
+            mtime: self.times.read().unwrap().time_for(path),
+            nlookup: 1,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay_store::AttrChange;
+    use git2::Signature;
+
+    // Repository whose only commit holds `src/lib.rs` and `README`
+    fn repo(name: &str) -> (PathBuf, Repository, git2::Oid) {
+        let dir = std::env::temp_dir().join(format!("gitfs-nodes-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let head = {
+            let mut src = repo.treebuilder(None).unwrap();
+            src.insert("lib.rs", repo.blob(b"fn main() {}").unwrap(), FileMode::Blob.into()).unwrap();
+            let src = src.write().unwrap();
+            let mut root = repo.treebuilder(None).unwrap();
+            root.insert("src", src, FileMode::Tree.into()).unwrap();
+            root.insert("README", repo.blob(b"readme").unwrap(), FileMode::Blob.into()).unwrap();
+            let tree = repo.find_tree(root.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+        };
+        (dir, repo, head)
+    }
+
+    #[test]
+    fn nodes_live_until_every_lookup_is_forgotten() {
+        let (dir, repo, head) = repo("forget");
+        let store = OverlayStore::new(dir.join("overlay")).unwrap();
+        let cache = NodeCache::new(CommitTimes::new(&repo, head, None).unwrap());
+        let lookup = |path: &str| cache.lookup_path(Path::new(path), &store, &repo, head).unwrap();
+
+        let ino = lookup("src/lib.rs").ino;
+        assert_eq!(lookup("src/lib.rs").ino, ino);
+        assert_eq!(cache.get_node(&ino).unwrap().nlookup, 2);
+
+        cache.forget(ino, 1);
+        assert_eq!(cache.get_node(&ino).unwrap().nlookup, 1);
+        cache.forget(ino, 1);
+        assert!(cache.get_node(&ino).is_none());
+        assert_eq!(cache.get_ino_by_path(Path::new("src/lib.rs")), None);
+
+        // The root is never freed
+        cache.forget(ROOT_INO, 10);
+        assert!(cache.get_node(&ROOT_INO).is_some());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn forgotten_paths_get_a_fresh_inode() {
+        let (dir, repo, head) = repo("reuse");
+        let store = OverlayStore::new(dir.join("overlay")).unwrap();
+        let cache = NodeCache::new(CommitTimes::new(&repo, head, None).unwrap());
+
+        let first = cache.lookup_path(Path::new("README"), &store, &repo, head).unwrap();
+        assert_eq!((first.kind, first.size, first.nlookup), (FileType::RegularFile, 6, 1));
+        cache.forget(first.ino, 1);
+
+        let again = cache.lookup_path(Path::new("README"), &store, &repo, head).unwrap();
+        assert_ne!(again.ino, first.ino);
+        assert_eq!(cache.live_entries(), vec![(again.ino, Some(ROOT_INO), "README".into())]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn chmod_chown_and_utimens_survive_forget() {
+        let (dir, repo, head) = repo("setattr");
+        let store = OverlayStore::new(dir.join("overlay")).unwrap();
+        let cache = NodeCache::new(CommitTimes::new(&repo, head, None).unwrap());
+        let path = Path::new("README");
+        let node = cache.lookup_path(path, &store, &repo, head).unwrap();
+        assert_eq!(cache.node_to_attr(&node, None).perm, 0o644);
+
+        let mtime = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        let change = AttrChange { perm: Some(0o750), uid: Some(1234), gid: Some(5678), atime: None, mtime: Some(mtime) };
+        store.set_attrs(path, node.kind, &change);
+        // chown alone keeps the mode
+        store.set_attrs(path, node.kind, &AttrChange { uid: Some(4321), ..Default::default() });
+        cache.forget(node.ino, 1);
+
+        let node = cache.lookup_path(path, &store, &repo, head).unwrap();
+        let entry = store.get(path).unwrap();
+        let attr = cache.node_to_attr(&node, Some(&entry));
+        assert_eq!((attr.perm, attr.uid, attr.gid, attr.mtime), (0o750, 4321, 5678, mtime));
+        assert_eq!(attr.size, 6);
+        assert_eq!(node.git_mode, Some(FileMode::BlobExecutable));
+        let _ = std::fs::remove_dir_all(&dir);
     }
 }
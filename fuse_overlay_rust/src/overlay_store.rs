@@ -0,0 +1,529 @@
+use dashmap::DashMap;
+use fuser::FileType;
+use git2::{FileMode, Oid};
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+use crate::metrics::debug;
+use crate::types::perm_to_git_mode;
+
+// Held by the mount owning a data directory for as long as it runs
+const LOCK_FILE: &str = "lock";
+// Serializes creating data directories with cleaning up stale ones
+const SETUP_LOCK: &str = "setup.lock";
+// Parent of the entries of unlinked files that are still open; no file name contains NUL
+const ORPHAN_DIR: &str = "\0unlinked";
+
+/// Entries created or modified through the mount. Nodes in `NodeCache` come and
+/// go with the kernel's lookup counts, so this is where the kind and metadata of
+/// an overlay entry are remembered between lookups.
+#[derive(Clone)]
+pub struct OverlayEntry {
+    pub kind: FileType,
+    pub atime: SystemTime,
+    pub mtime: SystemTime,
+    pub ctime: SystemTime,
+    // Permission bits and ownership set through the mount; None keeps the git-derived defaults
+    pub perm: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    // Content was written through the mount. A changed mode is found by comparing
+    // `git_mode()` with HEAD, so changing it back leaves the file clean.
+    pub modified: bool,
+    pub xattrs: BTreeMap<OsString, Vec<u8>>,
+    // Sparse backing file holding the content of a regular file once it has been written
+    pub data_id: Option<u64>,
+    // Content is this git blob until the first write (set by copy_file_range, no data copied)
+    pub base_oid: Option<Oid>,
+    // Names sharing this entry's backing file; every name holds an identical copy of the entry
+    pub nlink: u32,
+    // Device number of character and block device nodes
+    pub rdev: u32,
+}
+
+impl OverlayEntry {
+    pub fn new(kind: FileType) -> Self {
+        let now = SystemTime::now();
+        Self {
+            kind,
+            atime: now,
+            mtime: now,
+            ctime: now,
+            perm: None,
+            uid: None,
+            gid: None,
+            modified: false,
+            xattrs: BTreeMap::new(),
+            data_id: None,
+            base_oid: None,
+            nlink: 1,
+            rdev: 0,
+        }
+    }
+
+    /// FIFOs, sockets and device nodes only exist in the mount and have no git
+    /// representation, so they are left out of anything committed from the overlay.
+    pub fn committable(&self) -> bool {
+        matches!(self.kind, FileType::RegularFile | FileType::Directory | FileType::Symlink)
+    }
+
+    /// Mode a regular file would be committed with.
+    pub fn git_mode(&self) -> Option<FileMode> {
+        match self.kind {
+            FileType::RegularFile => self.perm.map(perm_to_git_mode),
+            _ => None,
+        }
+    }
+}
+
+/// Metadata changed through chmod, chown and utimens; None leaves a field as it is.
+#[derive(Default)]
+pub struct AttrChange {
+    pub perm: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+}
+
+/// Overlay entries plus the content of files written through the mount. Content
+/// lives in one sparse file per entry under `data_dir`, so reads and writes only
+/// touch the affected range and holes are preserved.
+pub struct OverlayStore {
+    entries: DashMap<PathBuf, OverlayEntry>,
+    data_dir: PathBuf,
+    next_data_id: AtomicU64,
+    // Paths of hard-linked files by data id; only ids with more than one name are listed
+    links: DashMap<u64, Vec<PathBuf>>,
+    // Disk space of each backing file as of its last change, and their total
+    allocated: DashMap<u64, u64>,
+    used: AtomicU64,
+    // flock on `data_dir`'s lock file, marking it as in use
+    _lock: File,
+}
+
+impl OverlayStore {
+    /// Backing files are scratch space for one mount, kept in a directory of
+    /// its own under `root` so several mounts of a repository can coexist.
+    /// Directories left by mounts that are no longer running are removed.
+    pub fn new(root: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&root)?;
+        let _setup = lock_file(&root.join(SETUP_LOCK), true)?;
+
+        for dir in fs::read_dir(&root)?.flatten() {
+            if !dir.file_type().is_ok_and(|t| t.is_dir()) {
+                continue;
+            }
+            if try_lock(&dir.path().join(LOCK_FILE))?.is_some() {
+                debug!("[OVERLAY] removing stale {:?}", dir.path());
+                fs::remove_dir_all(dir.path())?;
+            }
+        }
+
+        // Anything still there is in use, possibly by a process with our pid in another namespace
+        let pid = std::process::id();
+        let mut data_dir = root.join(pid.to_string());
+        let mut attempt = 0;
+        loop {
+            match fs::create_dir(&data_dir) {
+                Ok(()) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    attempt += 1;
+                    data_dir = root.join(format!("{}.{}", pid, attempt));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let lock = try_lock(&data_dir.join(LOCK_FILE))?
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EWOULDBLOCK))?;
+
+        Ok(Self {
+            entries: DashMap::new(),
+            data_dir,
+            next_data_id: AtomicU64::new(1),
+            links: DashMap::new(),
+            allocated: DashMap::new(),
+            used: AtomicU64::new(0),
+            _lock: lock,
+        })
+    }
+
+    pub fn insert(&self, path: PathBuf, entry: OverlayEntry) {
+        self.entries.insert(path, entry);
+    }
+
+    pub fn get(&self, path: &Path) -> Option<OverlayEntry> {
+        self.entries.get(path).map(|e| e.clone())
+    }
+
+    /// Apply `f` to the entry at `path`, creating one of `kind` if the path was only in git so far.
+    pub fn update<F: FnOnce(&mut OverlayEntry)>(&self, path: &Path, kind: FileType, f: F) -> OverlayEntry {
+        let entry = {
+            let mut entry = self.entries
+                .entry(path.to_path_buf())
+                .or_insert_with(|| OverlayEntry::new(kind));
+            f(&mut entry);
+            entry.clone()
+        };
+
+        // Hard links are one inode, so the change shows up under every name
+        for other in self.links_of(path) {
+            if let Some(mut linked) = self.entries.get_mut(&other) {
+                *linked = entry.clone();
+            }
+        }
+        entry
+    }
+
+    /// Apply chmod, chown or utimens to the entry at `path`, which also sets its ctime.
+    pub fn set_attrs(&self, path: &Path, kind: FileType, change: &AttrChange) -> OverlayEntry {
+        let now = SystemTime::now();
+        self.update(path, kind, |entry| {
+            entry.perm = change.perm.or(entry.perm);
+            entry.uid = change.uid.or(entry.uid);
+            entry.gid = change.gid.or(entry.gid);
+            entry.atime = change.atime.unwrap_or(entry.atime);
+            entry.mtime = change.mtime.unwrap_or(entry.mtime);
+            entry.ctime = now;
+        })
+    }
+
+    /// Record a content change.
+    pub fn touch(&self, path: &Path) {
+        let now = SystemTime::now();
+        self.update(path, FileType::RegularFile, |entry| {
+            entry.mtime = now;
+            entry.ctime = now;
+            entry.modified = true;
+        });
+    }
+
+    pub fn remove(&self, path: &Path) -> Option<OverlayEntry> {
+        let (_, entry) = self.entries.remove(path)?;
+        if let Some(id) = entry.data_id {
+            self.release_data(id, path);
+        }
+        Some(entry)
+    }
+
+    /// Move the entry at `path`, unlinked while inode `ino` is still open, to a
+    /// name no lookup or readdir reaches, keeping its content for the open handles.
+    pub fn orphan(&self, path: &Path, ino: u64) -> PathBuf {
+        let orphan = Path::new(ORPHAN_DIR).join(ino.to_string());
+        self.rename(path, &orphan);
+        orphan
+    }
+
+    pub fn is_orphan(path: &Path) -> bool {
+        path.starts_with(ORPHAN_DIR)
+    }
+
+    /// Make `new_path` another name for the file at `path`, which must already
+    /// have a backing file.
+    pub fn link(&self, path: &Path, new_path: &Path) -> io::Result<()> {
+        let entry = self.get(path).ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+        let id = entry.data_id.ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+
+        let names = {
+            let mut names = self.links.entry(id).or_insert_with(|| vec![path.to_path_buf()]);
+            names.push(new_path.to_path_buf());
+            names.clone()
+        };
+        self.entries.insert(new_path.to_path_buf(), entry);
+        self.set_nlink(&names, names.len() as u32);
+        Ok(())
+    }
+
+    /// Other names of the hard-linked file at `path`.
+    pub fn links_of(&self, path: &Path) -> Vec<PathBuf> {
+        let Some(id) = self.entries.get(path).and_then(|e| e.data_id) else {
+            return Vec::new();
+        };
+        self.links
+            .get(&id)
+            .map(|names| names.iter().filter(|p| p.as_path() != path).cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn has_data(&self, path: &Path) -> bool {
+        self.entries.get(path).is_some_and(|e| e.data_id.is_some())
+    }
+
+    /// Open the backing file of `path`, if its content lives in the overlay.
+    pub fn data_file(&self, path: &Path) -> Option<io::Result<File>> {
+        let data_path = self.data_path_of(path)?;
+        Some(OpenOptions::new().read(true).write(true).open(data_path))
+    }
+
+    pub fn data_path_of(&self, path: &Path) -> Option<PathBuf> {
+        let id = self.entries.get(path)?.data_id?;
+        Some(self.data_path(id))
+    }
+
+    pub fn data_size(&self, path: &Path) -> Option<u64> {
+        let file = self.data_file(path)?.ok()?;
+        file.metadata().ok().map(|m| m.len())
+    }
+
+    /// Give `path` a new backing file holding `content`. The file is written in
+    /// full before it is published on the entry, so readers see either the old
+    /// content or the new, never a partial copy.
+    pub fn replace_data(&self, path: &Path, content: &[u8]) -> io::Result<()> {
+        let id = self.next_data_id.fetch_add(1, Ordering::Relaxed);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(self.data_path(id))?;
+        file.write_all_at(content, 0)?;
+        self.account(id, &file.metadata()?);
+
+        let mut previous = None;
+        self.update(path, FileType::RegularFile, |entry| {
+            previous = entry.data_id.replace(id);
+            entry.base_oid = None;
+        });
+        if let Some(old_id) = previous {
+            self.release_data(old_id, path);
+        }
+        Ok(())
+    }
+
+    /// Make `path` share the content of git blob `oid` without copying any data.
+    pub fn reference_blob(&self, path: &Path, oid: Oid) {
+        let mut previous = None;
+        self.update(path, FileType::RegularFile, |entry| {
+            previous = entry.data_id.take();
+            entry.base_oid = Some(oid);
+        });
+        if let Some(old_id) = previous {
+            self.release_data(old_id, path);
+        }
+    }
+
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Note a change to `file`, the backing file of `path`, in the disk space
+    /// total. Returns the file's length.
+    pub fn record_usage(&self, path: &Path, file: &File) -> io::Result<u64> {
+        let meta = file.metadata()?;
+        if let Some(id) = self.entries.get(path).and_then(|e| e.data_id) {
+            self.account(id, &meta);
+        }
+        Ok(meta.len())
+    }
+
+    /// Disk space taken by backing files; holes do not count.
+    pub fn used_bytes(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn account(&self, id: u64, meta: &fs::Metadata) {
+        let bytes = meta.blocks() * 512;
+        let previous = self.allocated.insert(id, bytes).unwrap_or(0);
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+        self.used.fetch_sub(previous, Ordering::Relaxed);
+    }
+
+    /// statvfs of the filesystem the backing files live on.
+    pub fn backing_stats(&self) -> io::Result<libc::statvfs> {
+        let path = CString::new(self.data_dir.as_os_str().as_bytes())?;
+        let mut stats: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stats) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stats)
+    }
+
+    fn data_path(&self, id: u64) -> PathBuf {
+        self.data_dir.join(id.to_string())
+    }
+
+    fn delete_data(&self, id: u64) {
+        let _ = fs::remove_file(self.data_path(id));
+        if let Some((_, bytes)) = self.allocated.remove(&id) {
+            self.used.fetch_sub(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Drop `path`'s claim on backing file `id`; the file goes once no name is left.
+    fn release_data(&self, id: u64, path: &Path) {
+        let remaining = match self.links.get_mut(&id) {
+            Some(mut names) => {
+                names.retain(|p| p != path);
+                names.clone()
+            }
+            None => Vec::new(),
+        };
+        if remaining.len() <= 1 {
+            self.links.remove(&id);
+        }
+        if remaining.is_empty() {
+            self.delete_data(id);
+        } else {
+            self.set_nlink(&remaining, remaining.len() as u32);
+        }
+    }
+
+    fn set_nlink(&self, names: &[PathBuf], nlink: u32) {
+        let now = SystemTime::now();
+        for name in names {
+            if let Some(mut entry) = self.entries.get_mut(name) {
+                entry.nlink = nlink;
+                entry.ctime = now;
+            }
+        }
+    }
+
+    /// Move an entry and, for directories, everything below it.
+    pub fn rename(&self, old_path: &Path, new_path: &Path) {
+        let moved: Vec<PathBuf> = self.entries
+            .iter()
+            .filter(|e| e.key().starts_with(old_path))
+            .map(|e| e.key().clone())
+            .collect();
+
+        for path in moved {
+            if let Some((_, entry)) = self.entries.remove(&path) {
+                let target = match path.strip_prefix(old_path) {
+                    Ok(rest) if !rest.as_os_str().is_empty() => new_path.join(rest),
+                    _ => new_path.to_path_buf(),
+                };
+                // Replaced destination loses its content
+                self.remove(&target);
+                if let Some(id) = entry.data_id
+                    && let Some(mut names) = self.links.get_mut(&id)
+                {
+                    for name in names.iter_mut().filter(|p| **p == path) {
+                        *name = target.clone();
+                    }
+                }
+                self.entries.insert(target, entry);
+            }
+        }
+    }
+
+    /// Direct children of `dir` as (name, entry) pairs.
+    pub fn children(&self, dir: &Path) -> Vec<(String, OverlayEntry)> {
+        self.entries
+            .iter()
+            .filter(|e| e.key().parent() == Some(dir))
+            .filter_map(|e| {
+                let name = e.key().file_name()?.to_str()?.to_string();
+                Some((name, e.value().clone()))
+            })
+            .collect()
+    }
+}
+
+impl Drop for OverlayStore {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.data_dir);
+    }
+}
+
+/// Take an exclusive flock on `path`, creating the file if needed. None when
+/// another process holds it; the lock lasts as long as the returned file.
+pub fn try_lock(path: &Path) -> io::Result<Option<File>> {
+    match lock_file(path, false) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::EWOULDBLOCK) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn lock_file(path: &Path, wait: bool) -> io::Result<File> {
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    let op = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+    if unsafe { libc::flock(file.as_raw_fd(), op) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mounts_keep_separate_data_directories() {
+        let root = std::env::temp_dir().join(format!("gitfs-overlay-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let stale = root.join("1");
+        fs::create_dir_all(&stale).unwrap();
+        fs::write(stale.join("7"), b"left behind").unwrap();
+
+        let first = OverlayStore::new(root.clone()).unwrap();
+        assert!(!stale.exists());
+        let second = OverlayStore::new(root.clone()).unwrap();
+        assert_ne!(first.data_dir, second.data_dir);
+
+        first.replace_data(Path::new("a"), b"first").unwrap();
+        drop(second);
+        assert_eq!(fs::read(first.data_path_of(Path::new("a")).unwrap()).unwrap(), b"first");
+        let data_dir = first.data_dir.clone();
+        drop(first);
+        assert!(!data_dir.exists());
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn orphaned_entry_keeps_its_content_out_of_sight() {
+        let root = std::env::temp_dir().join(format!("gitfs-orphan-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = OverlayStore::new(root.clone()).unwrap();
+        store.replace_data(Path::new("a"), b"still open").unwrap();
+
+        let orphan = store.orphan(Path::new("a"), 5);
+        assert!(OverlayStore::is_orphan(&orphan));
+        assert!(store.get(Path::new("a")).is_none());
+        assert!(store.children(Path::new("")).is_empty());
+        let data_path = store.data_path_of(&orphan).unwrap();
+        assert_eq!(fs::read(&data_path).unwrap(), b"still open");
+
+        assert!(store.used_bytes() > 0);
+
+        store.remove(&orphan);
+        assert!(!data_path.exists());
+        assert_eq!(store.used_bytes(), 0);
+        drop(store);
+        let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn used_bytes_follow_writes_truncation_and_removal() {
+        let root = std::env::temp_dir().join(format!("gitfs-usage-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let store = OverlayStore::new(root.clone()).unwrap();
+        let a = Path::new("a");
+        assert_eq!(store.used_bytes(), 0);
+
+        store.replace_data(a, &[7u8; 64 * 1024]).unwrap();
+        let written = store.used_bytes();
+        assert!(written >= 64 * 1024);
+        store.replace_data(Path::new("b"), b"b").unwrap();
+        let both = store.used_bytes();
+        assert!(both > written);
+
+        let file = store.data_file(a).unwrap().unwrap();
+        file.set_len(0).unwrap();
+        assert_eq!(store.record_usage(a, &file).unwrap(), 0);
+        assert_eq!(store.used_bytes(), both - written);
+
+        store.remove(Path::new("b"));
+        assert_eq!(store.used_bytes(), 0);
+        assert_eq!(store.entry_count(), 1);
+        drop(store);
+        let _ = fs::remove_dir_all(&root);
+    }
+}
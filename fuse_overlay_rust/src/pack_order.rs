@@ -0,0 +1,210 @@
+use git2::{Oid, Repository};
+use std::cmp::Reverse;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use crate::metrics::debug;
+
+const IDX_MAGIC: [u8; 4] = [0xff, b't', b'O', b'c'];
+// Magic, version, then the 256-entry fanout table
+const HEADER_LEN: u64 = 8 + 256 * 4;
+const SHA_LEN: u64 = 20;
+// Offsets with the top bit set index the table of 64-bit offsets
+const LARGE_OFFSET: u32 = 0x8000_0000;
+// Same limit as git for chained alternates
+const MAX_ALTERNATE_DEPTH: usize = 5;
+
+// One version 2 pack index, searched in place rather than loaded
+struct PackIndex {
+    file: File,
+    fanout: [u32; 256],
+}
+
+/// Where objects sit in the repository's packfiles, from the pack `.idx`
+/// files of the object directory and its alternates. Reading objects in this
+/// order turns scattered seeks into one forward pass through each pack.
+#[derive(Default)]
+pub struct PackOrder {
+    packs: Vec<PackIndex>,
+}
+
+impl PackOrder {
+    /// Index files that cannot be read are skipped; their objects sort last.
+    pub fn load(objects_dir: &Path) -> Self {
+        let mut order = Self::default();
+        order.add_object_dir(objects_dir, 0);
+        // After gc nearly every object is in the largest pack, so most lookups
+        // end at the first index searched
+        order.packs.sort_by_key(|pack| Reverse(pack.object_count()));
+        debug!("[PACK] {} pack indexes under {:?}", order.packs.len(), objects_dir);
+        order
+    }
+
+    fn add_object_dir(&mut self, objects_dir: &Path, depth: usize) {
+        if let Ok(dir) = fs::read_dir(objects_dir.join("pack")) {
+            let mut idx_files: Vec<PathBuf> = dir
+                .flatten()
+                .map(|e| e.path())
+                .filter(|p| p.extension().is_some_and(|ext| ext == "idx"))
+                .collect();
+            idx_files.sort();
+            for path in idx_files {
+                match PackIndex::open(&path) {
+                    Ok(index) => self.packs.push(index),
+                    Err(e) => debug!("[PACK] skipping {:?}: {}", path, e),
+                }
+            }
+        }
+
+        if depth >= MAX_ALTERNATE_DEPTH {
+            return;
+        }
+        let Ok(alternates) = fs::read_to_string(objects_dir.join("info/alternates")) else { return; };
+        for line in alternates.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            self.add_object_dir(&objects_dir.join(line), depth + 1);
+        }
+    }
+
+    /// Pack number and offset of `oid`; None for loose or unknown objects.
+    pub fn location(&self, oid: Oid) -> Option<(usize, u64)> {
+        self.packs
+            .iter()
+            .enumerate()
+            .find_map(|(i, pack)| pack.offset_of(oid).map(|offset| (i, offset)))
+    }
+
+    /// Sort `items` by where their objects are stored, unpacked ones last and
+    /// otherwise in their original order.
+    pub fn sort<T>(&self, items: &mut [T], oid: impl Fn(&T) -> Oid) {
+        if self.packs.is_empty() {
+            return;
+        }
+        items.sort_by_cached_key(|item| self.location(oid(item)).unwrap_or((usize::MAX, 0)));
+    }
+}
+
+/// Object directory of `repo`. A linked worktree's git directory names the
+/// main one, whose objects every worktree shares, in its `commondir` file.
+pub fn objects_dir(repo: &Repository) -> PathBuf {
+    let git_dir = repo.path();
+    match fs::read_to_string(git_dir.join("commondir")) {
+        Ok(common) => git_dir.join(common.trim_end()).join("objects"),
+        Err(_) => git_dir.join("objects"),
+    }
+}
+
+impl PackIndex {
+    fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let mut header = [0u8; HEADER_LEN as usize];
+        file.read_exact_at(&mut header, 0)?;
+        if header[..4] != IDX_MAGIC || u32::from_be_bytes(header[4..8].try_into().unwrap()) != 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a version 2 pack index"));
+        }
+
+        let mut fanout = [0u32; 256];
+        for (i, count) in fanout.iter_mut().enumerate() {
+            let at = 8 + i * 4;
+            *count = u32::from_be_bytes(header[at..at + 4].try_into().unwrap());
+        }
+        Ok(Self { file, fanout })
+    }
+
+    fn object_count(&self) -> u32 {
+        self.fanout[255]
+    }
+
+    // Binary search of the sorted object names within the fanout bucket
+    fn offset_of(&self, oid: Oid) -> Option<u64> {
+        let name = oid.as_bytes();
+        let first = name[0] as usize;
+        let mut lo = if first == 0 { 0 } else { self.fanout[first - 1] };
+        let mut hi = self.fanout[first];
+        let mut entry = [0u8; SHA_LEN as usize];
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.file.read_exact_at(&mut entry, HEADER_LEN + mid as u64 * SHA_LEN).ok()?;
+            match entry.as_slice().cmp(name) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return self.offset_at(mid),
+            }
+        }
+        None
+    }
+
+    // Layout after the names: one CRC32 and one 32-bit offset per object,
+    // then the 64-bit offsets
+    fn offset_at(&self, index: u32) -> Option<u64> {
+        let count = self.object_count() as u64;
+        let offsets = HEADER_LEN + count * (SHA_LEN + 4);
+        let mut word = [0u8; 4];
+        self.file.read_exact_at(&mut word, offsets + index as u64 * 4).ok()?;
+        let offset = u32::from_be_bytes(word);
+        if offset & LARGE_OFFSET == 0 {
+            return Some(offset as u64);
+        }
+
+        let mut large = [0u8; 8];
+        let at = offsets + count * 4 + (offset & !LARGE_OFFSET) as u64 * 8;
+        self.file.read_exact_at(&mut large, at).ok()?;
+        Some(u64::from_be_bytes(large))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Repository;
+
+    #[test]
+    fn locates_packed_objects_in_pack_order() {
+        let dir = std::env::temp_dir().join(format!("gitfs-pack-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let packed: Vec<Oid> = (0..50).map(|i| repo.blob(format!("blob {}", i).as_bytes()).unwrap()).collect();
+
+        let mut builder = repo.packbuilder().unwrap();
+        for oid in &packed {
+            builder.insert_object(*oid, None).unwrap();
+        }
+        let mut pack = git2::Buf::new();
+        builder.write_buf(&mut pack).unwrap();
+        let odb = repo.odb().unwrap();
+        let mut writer = odb.packwriter().unwrap();
+        std::io::Write::write_all(&mut writer, &pack).unwrap();
+        writer.commit().unwrap();
+        let loose = repo.blob(b"not packed").unwrap();
+
+        let order = PackOrder::load(&repo.path().join("objects"));
+        assert!(packed.iter().all(|oid| order.location(*oid).is_some()));
+        assert_eq!(order.location(loose), None);
+
+        let mut oids = packed.clone();
+        oids.push(loose);
+        oids.reverse();
+        order.sort(&mut oids, |oid| *oid);
+        assert_eq!(oids.last(), Some(&loose));
+        let offsets: Vec<u64> = oids[..packed.len()].iter().map(|o| order.location(*o).unwrap().1).collect();
+        assert!(offsets.windows(2).all(|w| w[0] < w[1]));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn worktrees_share_the_main_object_directory() {
+        let dir = std::env::temp_dir().join(format!("gitfs-worktree-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(dir.join("main")).unwrap();
+        let sig = git2::Signature::now("test", "test@example.com").unwrap();
+        let tree = repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap();
+        let worktree = repo.worktree("linked", &dir.join("linked"), None).unwrap();
+        let linked = Repository::open_from_worktree(&worktree).unwrap();
+
+        let objects = fs::canonicalize(repo.path().join("objects")).unwrap();
+        assert_eq!(fs::canonicalize(objects_dir(&linked)).unwrap(), objects);
+        assert_eq!(fs::canonicalize(objects_dir(&repo)).unwrap(), objects);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
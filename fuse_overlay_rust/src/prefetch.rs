@@ -1,111 +1,906 @@
-use anyhow::Result;
-use git2::{ObjectType, Repository};
-use std::path::Path;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::path::PathBuf;
+use git2::{AttrCheckFlags, AttrValue, Delta, ObjectType, Oid, Repository, Tree, TreeWalkMode, TreeWalkResult};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 use crate::metrics::{debug, Metrics};
-use crate::cache::LruCache;
-
-#[allow(dead_code)]
-pub fn fetch_blob_from_git(repo: &Repository, path: &Path) -> Result<Vec<u8>, git2::Error> {
-    let head = repo.head()?.peel_to_commit()?;
-    let mut tree = head.tree()?;
-    for comp in path.iter() {
-        let comp_str = comp.to_str().ok_or_else(|| git2::Error::from_str("invalid UTF-8 in path"))?;
-        let entry_kind = tree.get_name(comp_str)
-            .ok_or_else(|| git2::Error::from_str("path not found"))?
-            .kind();
-        
-        if entry_kind == Some(ObjectType::Tree) {
-            let next_tree = tree.get_name(comp_str)
-                .ok_or_else(|| git2::Error::from_str("path not found"))?
-                .to_object(repo)?
-                .peel_to_tree()?;
-            tree = next_tree;
-        } else {
-            return Ok(tree.get_name(comp_str)
-                .ok_or_else(|| git2::Error::from_str("path not found"))?
-                .to_object(repo)?
-                .peel_to_blob()?
-                .content()
-                .to_vec());
-        }
-    }
-    Ok(Vec::new())
-}
-
-#[allow(dead_code)]
-pub fn prefetch_files(
-    repo_path: PathBuf,
-    paths: Vec<PathBuf>,
+use crate::cache::{LruCache, Prefetched};
+use crate::config::Config;
+use crate::profile::Profile;
+use crate::tree_meta::TreeMeta;
+use crate::pack_order::{self, PackOrder};
+
+// Foreground requests this long after the last one mean the filesystem is idle
+const IDLE_AFTER: Duration = Duration::from_millis(250);
+// How often a backed-off worker checks the foreground again
+const BACKOFF_POLL: Duration = Duration::from_millis(20);
+// GIT_ATTR_CHECK_INCLUDE_HEAD, which git2 has no flag for: also read .gitattributes from HEAD
+const ATTR_CHECK_INCLUDE_HEAD: u32 = 1 << 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    // Speculative, e.g. predicted from earlier access traces
+    Low,
+    Normal,
+    High,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Target {
+    // Every blob in the directory and in subdirectories down to the given depth
+    Directory(PathBuf, u32),
+    // Listing and blob sizes of the directory, without blob content
+    Metadata(PathBuf),
+    // One file, tagged with why it is wanted
+    File(PathBuf, Prefetched),
+    // A file asked for by a warm-up, cached as if it had been read; true skips
+    // the size and attribute filters
+    Warm(PathBuf, bool),
+    // Everything in the tree the prefetch profile covers
+    Profile,
+    // Files changed in the given number of commits up to HEAD
+    History(usize),
+    // Rescan the pack indexes, as fetches and gc repack
+    Packs,
+}
+
+struct Job {
+    target: Target,
+    head: Oid,
+    priority: Priority,
+    seq: u64,
+}
+
+// Highest priority first, then oldest first
+impl Ord for Job {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.seq == other.seq
+    }
+}
+
+impl Eq for Job {}
+
+/// Completion count of a group of requests, for callers that wait on them.
+/// Cancelled requests count as done.
+pub struct Batch {
+    total: usize,
+    done: Mutex<usize>,
+    progressed: Condvar,
+}
+
+impl Batch {
+    fn complete(&self) {
+        *self.done.lock().unwrap() += 1;
+        self.progressed.notify_all();
+    }
+
+    pub fn done(&self) -> usize {
+        *self.done.lock().unwrap()
+    }
+
+    /// Block until every request is done (true) or `deadline` passes (false),
+    /// calling `progress(done, total)` every `interval` meanwhile.
+    pub fn wait(&self, deadline: Instant, interval: Duration, mut progress: impl FnMut(usize, usize)) -> bool {
+        loop {
+            let report_at = (Instant::now() + interval).min(deadline);
+            let mut done = self.done.lock().unwrap();
+            while *done < self.total {
+                let now = Instant::now();
+                if now >= report_at {
+                    break;
+                }
+                done = self.progressed.wait_timeout(done, report_at - now).unwrap().0;
+            }
+            if *done >= self.total {
+                return true;
+            }
+            if Instant::now() >= deadline {
+                return false;
+            }
+            let count = *done;
+            drop(done);
+            progress(count, self.total);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    heap: BinaryHeap<Job>,
+    // Live job per target; heap entries whose seq is not listed here were
+    // superseded or cancelled and are skipped when popped
+    pending: HashMap<Target, (u64, Priority)>,
+    // Targets a worker is on right now, so repeated requests collapse into it
+    running: HashMap<Target, u64>,
+    // Batches waiting for a target, completed when it finishes or is cancelled
+    waiters: HashMap<Target, Vec<Arc<Batch>>>,
+    // Speculative requests that found the queue full, oldest first, queued
+    // again once it has room and the foreground is not busy
+    parked: VecDeque<(Target, Oid, Priority)>,
+    // Bumped by cancel_all; work started under an older generation is dropped
+    generation: u64,
+    next_seq: u64,
+    shutdown: bool,
+}
+
+// Latency of recent foreground requests, in microseconds since `epoch`
+struct Foreground {
+    epoch: Instant,
+    // Moving average over roughly the last eight requests
+    latency_us: AtomicU64,
+    last_us: AtomicU64,
+}
+
+/// Times one foreground request from creation to drop.
+pub struct ForegroundTimer {
+    shared: Arc<Shared>,
+    started: Instant,
+}
+
+struct Shared {
+    queue: Mutex<Queue>,
+    ready: Condvar,
     overlay: Arc<LruCache>,
+    meta: Arc<TreeMeta>,
     metrics: Arc<Metrics>,
-) {
-    thread::spawn(move || {
-        let Ok(repo) = Repository::open(&repo_path) else { return; };
-        
-        for path in paths {
-            if overlay.contains_key(&path) {
-                continue;
+    // With a profile, directory prefetching only loads the paths it covers; the
+    // rest load on demand unless a trace, the history or a warm-up asks for them
+    profile: Option<Profile>,
+    // Larger blobs are left for on-demand reads
+    max_blob: u64,
+    foreground: Foreground,
+    // Normal and low priority work waits while foreground requests average
+    // more than this; zero never waits
+    backoff_latency: Duration,
+    // Queued jobs past which speculative requests are parked, and parked
+    // requests past which the oldest are dropped; zero for no limit
+    max_queue: usize,
+    // Blobs are read in pack order; rescanned when HEAD moves. None when the
+    // repository could not be opened, which leaves blobs in tree order
+    objects_dir: Option<PathBuf>,
+    packs: RwLock<PackOrder>,
+}
+
+/// Fixed pool of workers loading git blobs into the cache ahead of reads. Each
+/// worker keeps one repository handle for its lifetime; requests go through a
+/// priority queue that collapses duplicates.
+pub struct Prefetcher {
+    shared: Arc<Shared>,
+}
+
+impl Prefetcher {
+    pub fn new(
+        repo_path: PathBuf,
+        config: &Config,
+        profile: Option<Profile>,
+        overlay: Arc<LruCache>,
+        meta: Arc<TreeMeta>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        let objects_dir = match Repository::open(&repo_path) {
+            Ok(repo) => Some(pack_order::objects_dir(&repo)),
+            Err(e) => {
+                debug!("[PREFETCH] cannot open {:?} for pack order: {}", repo_path, e);
+                None
             }
+        };
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            overlay,
+            meta,
+            metrics,
+            profile,
+            max_blob: config.prefetch_max_blob,
+            foreground: Foreground {
+                epoch: Instant::now(),
+                latency_us: AtomicU64::new(0),
+                last_us: AtomicU64::new(0),
+            },
+            backoff_latency: Duration::from_millis(config.prefetch_backoff_ms),
+            max_queue: config.prefetch_max_queue,
+            packs: RwLock::new(objects_dir.as_deref().map(PackOrder::load).unwrap_or_default()),
+            objects_dir,
+        });
+
+        for id in 0..config.prefetch_workers.max(1) {
+            let shared = shared.clone();
+            let repo_path = repo_path.clone();
+            thread::spawn(move || {
+                let Ok(repo) = Repository::open(&repo_path) else {
+                    debug!("[PREFETCH] worker {} cannot open {:?}", id, repo_path);
+                    return;
+                };
+                shared.run(&repo);
+            });
+        }
+
+        Self { shared }
+    }
+
+    /// Start timing a foreground request; slow ones make prefetch back off.
+    pub fn foreground(&self) -> ForegroundTimer {
+        ForegroundTimer {
+            shared: self.shared.clone(),
+            started: Instant::now(),
+        }
+    }
+
+    /// Prefetch the blobs in `dir_path`; `depth` > 0 also descends that many levels of subdirectories.
+    pub fn prefetch_directory(&self, dir_path: PathBuf, depth: u32, head: Oid, priority: Priority) {
+        self.shared.push(Target::Directory(dir_path, depth), head, priority, None);
+    }
 
-            if let Ok(blob) = fetch_blob_from_git(&repo, &path) {
-                debug!("[PREFETCH] Cached blob for {:?} ({} bytes)", path, blob.len());
-                metrics.prefetch_count.fetch_add(1, Ordering::Relaxed);
-                metrics.prefetch_bytes.fetch_add(blob.len() as u64, Ordering::Relaxed);
-                overlay.insert(path.clone(), blob);
+    /// Read the tree and the sizes of the blobs in `dir_path` so stat needs no object reads.
+    pub fn prefetch_metadata(&self, dir_path: PathBuf, head: Oid, priority: Priority) {
+        self.shared.push(Target::Metadata(dir_path), head, priority, None);
+    }
+
+    /// Files the access trace expects to be read next, at low priority.
+    pub fn predict_files(&self, paths: Vec<PathBuf>, head: Oid) {
+        for path in paths {
+            self.shared.push(Target::File(path, Prefetched::Predicted), head, Priority::Low, None);
+        }
+    }
+
+    /// Load `paths` at `priority` into the cache's demand budget, returning a
+    /// batch to wait on. `unfiltered` loads blobs the size limit or
+    /// .gitattributes would keep out of prefetching.
+    pub fn warm_files(&self, paths: Vec<PathBuf>, head: Oid, priority: Priority, unfiltered: bool) -> Arc<Batch> {
+        let batch = Arc::new(Batch {
+            total: paths.len(),
+            done: Mutex::new(0),
+            progressed: Condvar::new(),
+        });
+        for path in paths {
+            self.shared.push(Target::Warm(path, unfiltered), head, priority, Some(&batch));
+        }
+        batch
+    }
+
+    /// Warm everything the profile covers in the background; no-op without a profile.
+    pub fn warm_profile(&self, head: Oid) {
+        if self.shared.profile.is_some() {
+            self.shared.push(Target::Profile, head, Priority::Low, None);
+        }
+    }
+
+    /// Warm the files changed in the last `commits` commits in the background; no-op for 0.
+    pub fn warm_history(&self, head: Oid, commits: usize) {
+        if commits > 0 {
+            self.shared.push(Target::History(commits), head, Priority::Low, None);
+        }
+    }
+
+    /// Drop queued work for `dir_path` and everything below it (removed or renamed away).
+    pub fn cancel(&self, dir_path: &Path) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.pending.retain(|target, _| !target.path().starts_with(dir_path));
+        queue.parked.retain(|(target, ..)| !target.path().starts_with(dir_path));
+        let Queue { waiters, pending, running, .. } = &mut *queue;
+        waiters.retain(|target, batches| {
+            let cancelled = target.path().starts_with(dir_path)
+                && !pending.contains_key(target)
+                && !running.contains_key(target);
+            if cancelled {
+                batches.iter().for_each(|b| b.complete());
             }
+            !cancelled
+        });
+    }
+
+    /// Drop all queued and running work, e.g. because HEAD moved.
+    pub fn cancel_all(&self) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        queue.generation += 1;
+        queue.pending.clear();
+        queue.parked.clear();
+        queue.heap.clear();
+        for (_, batches) in queue.waiters.drain() {
+            batches.iter().for_each(|b| b.complete());
         }
-    });
+        drop(queue);
+
+        // Reading every index is too slow for the request that moved HEAD
+        if self.shared.objects_dir.is_some() {
+            self.shared.push(Target::Packs, Oid::zero(), Priority::High, None);
+        }
+    }
 }
 
-pub fn prefetch_directory(
-    repo_path: PathBuf,
-    dir_path: PathBuf,
-    head: git2::Oid,
-    overlay: Arc<LruCache>,
-    metrics: Arc<Metrics>,
-) {
-    thread::spawn(move || {
-        let Ok(repo) = Repository::open(&repo_path) else { return; };
-        let Ok(commit) = repo.find_commit(head) else { return; };
-        let Ok(mut tree) = commit.tree() else { return; };
-        
-        for comp in dir_path.iter() {
-            let Some(comp_str) = comp.to_str() else { return; };
-            let next_tree = tree.get_name(comp_str)
-                .and_then(|entry| entry.to_object(&repo).ok())
-                .and_then(|obj| obj.peel_to_tree().ok());
-            let Some(next) = next_tree else { return; };
-            tree = next;
-        }
-        
-        debug!("[PREFETCH] Prefetching directory: {:?}", dir_path);
-        for entry in tree.iter() {
-            if entry.kind() != Some(ObjectType::Blob) {
-                continue;
-            }
-            
-            let Some(name) = entry.name() else { continue; };
-            let file_path = dir_path.join(name);
-            
-            if overlay.contains_key(&file_path) {
-                continue;
-            }
-            
-            if let Ok(obj) = entry.to_object(&repo) {
-                if let Ok(blob) = obj.peel_to_blob() {
-                    let content = blob.content().to_vec();
-                    debug!("[PREFETCH] Cached {:?} ({} bytes)", file_path, content.len());
-                    metrics.prefetch_count.fetch_add(1, Ordering::Relaxed);
-                    metrics.prefetch_bytes.fetch_add(content.len() as u64, Ordering::Relaxed);
-                    overlay.insert(file_path, content);
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().shutdown = true;
+        self.shared.ready.notify_all();
+    }
+}
+
+impl Drop for ForegroundTimer {
+    fn drop(&mut self) {
+        let fg = &self.shared.foreground;
+        let sample = self.started.elapsed().as_micros() as u64;
+        let average = fg.latency_us.load(Ordering::Relaxed);
+        fg.latency_us.store(average - average / 8 + sample / 8, Ordering::Relaxed);
+        fg.last_us.store(fg.epoch.elapsed().as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+impl Foreground {
+    // Slow and recent; an idle filesystem is never busy, whatever its last latency
+    fn busy(&self, threshold: Duration) -> bool {
+        let now = self.epoch.elapsed().as_micros() as u64;
+        let since_last = now.saturating_sub(self.last_us.load(Ordering::Relaxed));
+        since_last < IDLE_AFTER.as_micros() as u64
+            && self.latency_us.load(Ordering::Relaxed) > threshold.as_micros() as u64
+    }
+}
+
+impl Target {
+    fn path(&self) -> &Path {
+        match self {
+            Target::Directory(p, _) | Target::Metadata(p) | Target::File(p, _) | Target::Warm(p, _) => p,
+            Target::Profile | Target::History(_) | Target::Packs => Path::new(""),
+        }
+    }
+}
+
+impl Shared {
+    fn push(&self, target: Target, head: Oid, priority: Priority, batch: Option<&Arc<Batch>>) {
+        let mut queue = self.queue.lock().unwrap();
+        if let Some(batch) = batch {
+            queue.waiters.entry(target.clone()).or_default().push(batch.clone());
+        }
+        if queue.running.get(&target) == Some(&queue.generation) {
+            return;
+        }
+        // Already queued: only a higher priority re-queues it
+        if let Some(&(_, queued)) = queue.pending.get(&target)
+            && queued >= priority
+        {
+            return;
+        }
+        let parked = queue.parked.iter().position(|(t, ..)| *t == target);
+        let priority = match parked.and_then(|pos| queue.parked.remove(pos)) {
+            Some((_, _, earlier)) => earlier.max(priority),
+            None => priority,
+        };
+        if priority < Priority::High && self.queue_full(&queue) {
+            debug!("[PREFETCH] queue full, parking {:?}", target);
+            if queue.parked.len() >= self.max_queue {
+                let dropped = queue.parked.pop_front();
+                debug!("[PREFETCH] too much parked, dropping {:?}", dropped.map(|(t, ..)| t));
+            }
+            queue.parked.push_back((target, head, priority));
+            return;
+        }
+        Self::enqueue(&mut queue, target, head, priority);
+        self.ready.notify_one();
+    }
+
+    fn enqueue(queue: &mut Queue, target: Target, head: Oid, priority: Priority) {
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        queue.pending.insert(target.clone(), (seq, priority));
+        queue.heap.push(Job { target, head, priority, seq });
+    }
+
+    fn queue_full(&self, queue: &Queue) -> bool {
+        self.max_queue > 0 && queue.pending.len() >= self.max_queue
+    }
+
+    // Queue parked requests again while there is room and the foreground lets up
+    fn unpark(&self, queue: &mut Queue) {
+        if queue.parked.is_empty() || (!self.backoff_latency.is_zero() && self.foreground.busy(self.backoff_latency)) {
+            return;
+        }
+        while !self.queue_full(queue) {
+            let Some((target, head, priority)) = queue.parked.pop_front() else { break; };
+            if !queue.pending.contains_key(&target) {
+                Self::enqueue(queue, target, head, priority);
+            }
+        }
+    }
+
+    // Next live job and the generation it runs under; None on shutdown
+    fn pop(&self) -> Option<(Job, u64)> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if queue.shutdown {
+                return None;
+            }
+            self.unpark(&mut queue);
+            match queue.heap.pop() {
+                Some(job) => {
+                    if queue.pending.get(&job.target).map(|(seq, _)| *seq) != Some(job.seq) {
+                        continue;
+                    }
+                    queue.pending.remove(&job.target);
+                    let generation = queue.generation;
+                    queue.running.insert(job.target.clone(), generation);
+                    return Some((job, generation));
                 }
+                // Parked work is waiting for the foreground to settle
+                None if !queue.parked.is_empty() => queue = self.ready.wait_timeout(queue, BACKOFF_POLL).unwrap().0,
+                None => queue = self.ready.wait(queue).unwrap(),
             }
         }
-        
-        metrics.log();
-    });
+    }
+
+    fn finish(&self, target: &Target, generation: u64) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.running.get(target) == Some(&generation) {
+            queue.running.remove(target);
+        }
+        // A re-queued target keeps its waiters for the newer run
+        if !queue.pending.contains_key(target) {
+            for batch in queue.waiters.remove(target).unwrap_or_default() {
+                batch.complete();
+            }
+        }
+    }
+
+    fn is_current(&self, generation: u64) -> bool {
+        self.queue.lock().unwrap().generation == generation
+    }
+
+    // Wait out foreground load, then whether the work is still wanted. High
+    // priority work (listed directories, warm-ups) never waits.
+    fn keep_going(&self, generation: u64, priority: Priority) -> bool {
+        if priority < Priority::High && !self.backoff_latency.is_zero() {
+            let mut waited = Duration::ZERO;
+            while self.foreground.busy(self.backoff_latency) {
+                if self.queue.lock().unwrap().shutdown {
+                    return false;
+                }
+                thread::sleep(BACKOFF_POLL);
+                waited += BACKOFF_POLL;
+            }
+            if !waited.is_zero() {
+                debug!("[PREFETCH] backed off {:?} for foreground requests", waited);
+            }
+        }
+        self.is_current(generation)
+    }
+
+    fn run(&self, repo: &Repository) {
+        while let Some((job, generation)) = self.pop() {
+            let (head, priority) = (job.head, job.priority);
+            if self.keep_going(generation, priority) {
+                match &job.target {
+                    Target::Directory(dir, depth) => self.load_directory(repo, dir, *depth, head, generation, priority),
+                    Target::Metadata(dir) => self.load_metadata(repo, dir, head, generation, priority),
+                    Target::File(path, source) => self.load_file(repo, path, head, generation, *source),
+                    Target::Warm(path, unfiltered) => self.load_warm(repo, path, head, generation, *unfiltered),
+                    Target::Profile => self.load_profile(repo, head, generation, priority),
+                    Target::History(commits) => self.load_history(repo, *commits, head, generation, priority),
+                    Target::Packs => self.load_packs(),
+                }
+            }
+            self.finish(&job.target, generation);
+        }
+    }
+
+    // Breadth-first: all trees down to `depth` are read first, then their blobs
+    // level by level, so the nearest files are cached first
+    fn load_directory(&self, repo: &Repository, dir_path: &Path, depth: u32, head: Oid, generation: u64, priority: Priority) {
+        let Some(tree) = self.meta.tree_at(repo, head, dir_path) else { return; };
+
+        debug!("[PREFETCH] Prefetching directory: {:?} (depth {})", dir_path, depth);
+        let mut level = vec![(dir_path.to_path_buf(), tree)];
+        let mut blobs = Vec::new();
+        for d in 0..=depth {
+            let mut next = Vec::new();
+            let level_start = blobs.len();
+            for (path, tree) in &level {
+                if !self.keep_going(generation, priority) {
+                    debug!("[PREFETCH] cancelled {:?}", dir_path);
+                    return;
+                }
+                let Some(entries) = self.meta.entries(repo, *tree) else { continue; };
+                for entry in entries.iter() {
+                    match entry.kind {
+                        ObjectType::Blob => {
+                            let path = path.join(&entry.name);
+                            if self.profile.as_ref().is_none_or(|p| p.includes(&path)) {
+                                blobs.push((path, entry.oid));
+                            }
+                        }
+                        ObjectType::Tree if d < depth => {
+                            let path = path.join(&entry.name);
+                            if self.profile.as_ref().is_none_or(|p| p.covers_below(&path)) {
+                                next.push((path, entry.oid));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            // Pack order within each level keeps the nearest files first
+            self.pack_sort(&mut blobs[level_start..]);
+            level = next;
+        }
+
+        for (path, oid) in blobs {
+            if !self.keep_going(generation, priority) {
+                debug!("[PREFETCH] cancelled {:?}", dir_path);
+                return;
+            }
+            self.load_blob(repo, path, oid, generation, Prefetched::Speculative);
+        }
+
+        self.metrics.log();
+    }
+
+    fn load_metadata(&self, repo: &Repository, dir_path: &Path, head: Oid, generation: u64, priority: Priority) {
+        let Some(tree) = self.meta.tree_at(repo, head, dir_path) else { return; };
+        let Some(entries) = self.meta.entries(repo, tree) else { return; };
+        let mut blobs: Vec<(PathBuf, Oid)> = entries
+            .iter()
+            .filter(|e| e.kind == ObjectType::Blob)
+            .map(|e| (dir_path.join(&e.name), e.oid))
+            .collect();
+        self.pack_sort(&mut blobs);
+
+        for (_, oid) in blobs {
+            if !self.keep_going(generation, priority) {
+                return;
+            }
+            self.meta.blob_size(repo, oid);
+        }
+    }
+
+    fn load_file(&self, repo: &Repository, path: &Path, head: Oid, generation: u64, source: Prefetched) {
+        let Some(entry) = self.meta.entry_at(repo, head, path) else { return; };
+        self.load_blob(repo, path.to_path_buf(), entry.oid, generation, source);
+    }
+
+    fn load_warm(&self, repo: &Repository, path: &Path, head: Oid, generation: u64, unfiltered: bool) {
+        let Some(entry) = self.meta.entry_at(repo, head, path) else { return; };
+        if self.overlay.contains_key(&path.to_path_buf()) || (!unfiltered && self.filtered_out(repo, path, entry.oid)) {
+            return;
+        }
+        let Ok(blob) = repo.find_blob(entry.oid) else { return; };
+        self.insert(path.to_path_buf(), blob.content().to_vec(), generation, None);
+    }
+
+    fn load_profile(&self, repo: &Repository, head: Oid, generation: u64, priority: Priority) {
+        let Some(tree) = tree_at(repo, head, Path::new("")) else { return; };
+
+        debug!("[PREFETCH] Warming profile");
+        let mut blobs = Vec::new();
+        let _ = tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+            let (Some(profile), Some(name)) = (&self.profile, entry.name()) else { return TreeWalkResult::Ok; };
+            let path = Path::new(dir).join(name);
+            match entry.kind() {
+                Some(ObjectType::Tree) if !profile.covers_below(&path) => return TreeWalkResult::Skip,
+                Some(ObjectType::Blob) if profile.includes(&path) => blobs.push((path, entry.id())),
+                _ => {}
+            }
+            TreeWalkResult::Ok
+        });
+        self.pack_sort(&mut blobs);
+
+        for (path, oid) in blobs {
+            if !self.keep_going(generation, priority) {
+                debug!("[PREFETCH] profile warm cancelled");
+                return;
+            }
+            self.load_blob(repo, path, oid, generation, Prefetched::Speculative);
+        }
+
+        self.metrics.log();
+    }
+
+    // Commits without a parent are skipped: against nothing,
+    // every file counts as changed, and in a shallow clone that is the whole tree.
+    fn load_history(&self, repo: &Repository, commits: usize, head: Oid, generation: u64, priority: Priority) {
+        let Ok(mut walk) = repo.revwalk() else { return; };
+        if walk.push(head).and_then(|_| walk.simplify_first_parent()).is_err() {
+            return;
+        }
+
+        let mut seen = HashSet::new();
+        let mut changed = Vec::new();
+        for oid in walk.flatten().take(commits) {
+            if !self.keep_going(generation, priority) {
+                return;
+            }
+            let Ok(commit) = repo.find_commit(oid) else { continue; };
+            let Ok(parent) = commit.parent(0).and_then(|p| p.tree()) else { continue; };
+            let Ok(tree) = commit.tree() else { continue; };
+            let Ok(diff) = repo.diff_tree_to_tree(Some(&parent), Some(&tree), None) else { continue; };
+            for delta in diff.deltas().filter(|d| d.status() != Delta::Deleted) {
+                if let Some(path) = delta.new_file().path()
+                    && seen.insert(path.to_path_buf())
+                {
+                    changed.push(path.to_path_buf());
+                }
+            }
+        }
+
+        // As of HEAD: changed again since, or removed
+        let mut blobs: Vec<(PathBuf, Oid)> = changed
+            .into_iter()
+            .filter_map(|path| {
+                let entry = self.meta.entry_at(repo, head, &path)?;
+                (entry.kind == ObjectType::Blob).then_some((path, entry.oid))
+            })
+            .collect();
+        self.pack_sort(&mut blobs);
+
+        debug!("[PREFETCH] Warming {} files changed in the last {} commits", blobs.len(), commits);
+        for (path, oid) in blobs {
+            if !self.keep_going(generation, priority) {
+                debug!("[PREFETCH] history warm cancelled");
+                return;
+            }
+            self.load_blob(repo, path, oid, generation, Prefetched::History);
+        }
+
+        self.metrics.log();
+    }
+
+    fn load_packs(&self) {
+        if let Some(objects_dir) = &self.objects_dir {
+            let packs = PackOrder::load(objects_dir);
+            *self.packs.write().unwrap() = packs;
+        }
+    }
+
+    fn pack_sort(&self, blobs: &mut [(PathBuf, Oid)]) {
+        self.packs.read().unwrap().sort(blobs, |(_, oid)| *oid);
+    }
+
+    fn load_blob(&self, repo: &Repository, path: PathBuf, oid: Oid, generation: u64, source: Prefetched) {
+        if self.overlay.contains_key(&path) || self.filtered_out(repo, &path, oid) {
+            return;
+        }
+        let Ok(blob) = repo.find_blob(oid) else { return; };
+        self.insert(path, blob.content().to_vec(), generation, Some(source));
+    }
+
+    // Too large, or kept out by .gitattributes
+    fn filtered_out(&self, repo: &Repository, path: &Path, oid: Oid) -> bool {
+        // The object header gives the size without inflating the blob
+        if let Some(size) = self.meta.blob_size(repo, oid)
+            && size > self.max_blob
+        {
+            debug!("[PREFETCH] Skipping large blob {:?} ({} bytes)", path, size);
+            return true;
+        }
+        if excluded_by_attributes(repo, path) {
+            debug!("[PREFETCH] Skipping {:?} (excluded by .gitattributes)", path);
+            return true;
+        }
+        false
+    }
+
+    // None caches the content as if it had been read
+    fn insert(&self, path: PathBuf, content: Vec<u8>, generation: u64, source: Option<Prefetched>) {
+        // Checked under the queue lock so a cancel_all (HEAD switch) followed by
+        // a cache clear cannot be overtaken by a blob of the old tree
+        let queue = self.queue.lock().unwrap();
+        if queue.generation != generation {
+            return;
+        }
+        let len = content.len() as u64;
+        // Never replaces content cached in the meantime
+        let cached = match source {
+            Some(source) => self.overlay.insert_prefetched(path.clone(), content, source),
+            None => !self.overlay.contains_key(&path) && self.overlay.insert(path.clone(), content),
+        };
+        if cached {
+            debug!("[PREFETCH] Cached {:?} ({} bytes)", path, len);
+            let (count, bytes) = match source {
+                Some(Prefetched::Speculative) | None => (&self.metrics.prefetch_count, &self.metrics.prefetch_bytes),
+                Some(Prefetched::Predicted) => (&self.metrics.predicted_count, &self.metrics.predicted_bytes),
+                Some(Prefetched::History) => (&self.metrics.history_count, &self.metrics.history_bytes),
+            };
+            count.fetch_add(1, Ordering::Relaxed);
+            bytes.fetch_add(len, Ordering::Relaxed);
+        }
+        drop(queue);
+    }
+}
+
+// `-prefetch`, or `binary` for binaries; together with the size limit this keeps
+// large binaries out without inflating them
+fn excluded_by_attributes(repo: &Repository, path: &Path) -> bool {
+    let flags = AttrCheckFlags::from_bits_retain(AttrCheckFlags::FILE_THEN_INDEX.bits() | ATTR_CHECK_INCLUDE_HEAD);
+    let attr = |name| repo.get_attr(path, name, flags).map(AttrValue::from_string);
+    matches!(attr("prefetch"), Ok(AttrValue::False)) || matches!(attr("binary"), Ok(AttrValue::True))
+}
+
+fn tree_at<'r>(repo: &'r Repository, head: Oid, dir_path: &Path) -> Option<Tree<'r>> {
+    let tree = repo.find_commit(head).ok()?.tree().ok()?;
+    if dir_path.as_os_str().is_empty() {
+        return Some(tree);
+    }
+    let entry = tree.get_path(dir_path).ok()?;
+    repo.find_tree(entry.id()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{FileMode, Signature};
+
+    // Workers are left out so tests can drive the queue and the loaders directly
+    fn shared(max_queue: usize) -> Shared {
+        Shared {
+            queue: Mutex::new(Queue::default()),
+            ready: Condvar::new(),
+            overlay: Arc::new(LruCache::new(1 << 20, 1 << 20, 1000, 1 << 20)),
+            meta: Arc::new(TreeMeta::default()),
+            metrics: Arc::new(Metrics::default()),
+            profile: None,
+            max_blob: u64::MAX,
+            foreground: foreground(IDLE_AFTER * 2, Duration::ZERO),
+            backoff_latency: Duration::ZERO,
+            max_queue,
+            objects_dir: None,
+            packs: RwLock::new(PackOrder::default()),
+        }
+    }
+
+    fn foreground(idle_for: Duration, latency: Duration) -> Foreground {
+        Foreground {
+            epoch: Instant::now() - idle_for,
+            latency_us: AtomicU64::new(latency.as_micros() as u64),
+            last_us: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn busy_only_while_recent_requests_are_slow() {
+        let threshold = Duration::from_millis(10);
+        assert!(foreground(Duration::ZERO, Duration::from_millis(50)).busy(threshold));
+        assert!(!foreground(Duration::ZERO, Duration::from_millis(5)).busy(threshold));
+        // Slow, but nothing has come in for a while
+        assert!(!foreground(IDLE_AFTER * 2, Duration::from_millis(50)).busy(threshold));
+    }
+
+    #[test]
+    fn repeated_requests_collapse_into_one_job() {
+        let shared = shared(0);
+        let head = Oid::zero();
+        let target = Target::Directory(PathBuf::from("src"), 1);
+        let batch = Arc::new(Batch { total: 2, done: Mutex::new(0), progressed: Condvar::new() });
+
+        shared.push(target.clone(), head, Priority::Low, Some(&batch));
+        shared.push(target.clone(), head, Priority::Low, Some(&batch));
+        assert_eq!(shared.queue.lock().unwrap().heap.len(), 1);
+
+        // Only a higher priority queues it again; the older entry goes stale
+        shared.push(target.clone(), head, Priority::High, None);
+        assert_eq!(shared.queue.lock().unwrap().heap.len(), 2);
+        assert_eq!(shared.queue.lock().unwrap().pending[&target].1, Priority::High);
+
+        let (job, generation) = shared.pop().unwrap();
+        assert_eq!((job.target.clone(), job.priority), (target.clone(), Priority::High));
+        // Requests for the running target join it
+        shared.push(target.clone(), head, Priority::High, None);
+        assert!(shared.queue.lock().unwrap().pending.is_empty());
+
+        shared.finish(&job.target, generation);
+        assert_eq!(batch.done(), 2);
+        assert!(shared.queue.lock().unwrap().running.is_empty());
+    }
+
+    // Commit on top of HEAD, writing each path with the given content or removing it
+    fn commit(repo: &Repository, changes: &[(&str, Option<&str>)]) -> Oid {
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let base = match &parent {
+            Some(commit) => commit.tree().unwrap(),
+            None => repo.find_tree(repo.treebuilder(None).unwrap().write().unwrap()).unwrap(),
+        };
+        let mut update = git2::build::TreeUpdateBuilder::new();
+        for (path, content) in changes {
+            match content {
+                Some(content) => update.upsert(*path, repo.blob(content.as_bytes()).unwrap(), FileMode::Blob),
+                None => update.remove(*path),
+            };
+        }
+        let tree = repo.find_tree(update.create_updated(repo, &base).unwrap()).unwrap();
+        let sig = Signature::now("test", "test@example.com").unwrap();
+        let parents: Vec<_> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &sig, &sig, "test", &tree, &parents).unwrap()
+    }
+
+    fn repo(name: &str) -> (PathBuf, Repository) {
+        let dir = std::env::temp_dir().join(format!("gitfs-prefetch-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        (dir, repo)
+    }
+
+    #[test]
+    fn directories_load_down_to_their_depth() {
+        let (dir, repo) = repo("depth");
+        let head = commit(&repo, &[("a/x", Some("x")), ("a/b/y", Some("y")), ("a/b/c/z", Some("z"))]);
+
+        let shared = shared(0);
+        shared.load_directory(&repo, Path::new("a"), 1, head, 0, Priority::High);
+        let cached = |path: &str| shared.overlay.contains_key(&PathBuf::from(path));
+        assert!(cached("a/x") && cached("a/b/y"));
+        assert!(!cached("a/b/c/z"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn history_loads_files_changed_in_recent_commits() {
+        let (dir, repo) = repo("history");
+        commit(&repo, &[("old", Some("1")), ("kept", Some("1")), ("gone", Some("1"))]);
+        commit(&repo, &[("old", Some("2")), ("gone", Some("2"))]);
+        commit(&repo, &[("new", Some("3")), ("gone", None)]);
+        let head = commit(&repo, &[("new", Some("4"))]);
+        let cached = |shared: &Shared| {
+            let mut paths: Vec<_> = ["old", "kept", "gone", "new"]
+                .into_iter()
+                .filter(|p| shared.overlay.contains_key(&PathBuf::from(p)))
+                .collect();
+            paths.sort();
+            paths
+        };
+
+        let recent = shared(0);
+        recent.load_history(&repo, 2, head, 0, Priority::Low);
+        assert_eq!(cached(&recent), ["new"]);
+
+        // Removed files are not in HEAD, and the root commit counts as no change
+        let all = shared(0);
+        all.load_history(&repo, 10, head, 0, Priority::Low);
+        assert_eq!(cached(&all), ["new", "old"]);
+        assert_eq!(all.metrics.history_count.load(Ordering::Relaxed), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn speculative_requests_park_when_full_and_drop_the_oldest() {
+        let shared = shared(2);
+        let head = Oid::zero();
+        let file = |name: &str| Target::File(PathBuf::from(name), Prefetched::Speculative);
+        shared.push(file("a"), head, Priority::Low, None);
+        shared.push(file("b"), head, Priority::Normal, None);
+
+        // Normal requests park like low ones; urgent ones still queue
+        shared.push(file("c"), head, Priority::Normal, None);
+        shared.push(file("d"), head, Priority::Low, None);
+        shared.push(file("e"), head, Priority::High, None);
+        let parked = |shared: &Shared| -> Vec<_> {
+            shared.queue.lock().unwrap().parked.iter().map(|(t, _, p)| (t.clone(), *p)).collect()
+        };
+        assert_eq!(parked(&shared), [(file("c"), Priority::Normal), (file("d"), Priority::Low)]);
+        assert_eq!(shared.queue.lock().unwrap().pending.len(), 3);
+
+        // Parking past the limit drops the oldest
+        shared.push(file("f"), head, Priority::Low, None);
+        assert_eq!(parked(&shared), [(file("d"), Priority::Low), (file("f"), Priority::Low)]);
+
+        // Room again: parked requests queue at the priority they asked for
+        for _ in 0..3 {
+            shared.pop().unwrap();
+        }
+        let mut queue = shared.queue.lock().unwrap();
+        shared.unpark(&mut queue);
+        assert!(queue.parked.is_empty());
+        assert_eq!(queue.pending[&file("d")].1, Priority::Low);
+    }
 }
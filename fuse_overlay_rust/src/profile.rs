@@ -0,0 +1,267 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+struct Pattern {
+    glob: Vec<u8>,
+    negated: bool,
+    // Trailing slash: matches directories only, i.e. everything below them
+    dir_only: bool,
+}
+
+/// Prefetch profile in gitignore / sparse-checkout syntax: one pattern per line,
+/// `#` comments, `!` to exclude again, `*`, `?`, `[...]` and `**`. A pattern
+/// without a slash matches a name at any depth; a pattern matching a directory
+/// covers everything below it. The last matching pattern decides.
+pub struct Profile {
+    patterns: Vec<Pattern>,
+}
+
+impl Profile {
+    /// None when there is no profile file.
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Some(Self::parse(&text))),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn parse(text: &str) -> Self {
+        let patterns = text
+            .lines()
+            .map(str::trim_end)
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|line| {
+                let (negated, line) = match line.strip_prefix('!') {
+                    Some(rest) => (true, rest),
+                    None => (false, line.strip_prefix('\\').unwrap_or(line)),
+                };
+                let (dir_only, line) = match line.strip_suffix('/') {
+                    Some(rest) => (true, rest),
+                    None => (false, line),
+                };
+                let glob = match line.strip_prefix('/') {
+                    Some(anchored) => anchored.to_string(),
+                    None if line.contains('/') => line.to_string(),
+                    None => format!("**/{}", line),
+                };
+                Pattern { glob: glob.into_bytes(), negated, dir_only }
+            })
+            .collect();
+        Self { patterns }
+    }
+
+    /// Whether the file at `path` (relative to the repository root) is covered.
+    pub fn includes(&self, path: &Path) -> bool {
+        let Some(path) = path.to_str() else { return false };
+        let mut included = false;
+        for pattern in &self.patterns {
+            if pattern.matches(path) {
+                included = !pattern.negated;
+            }
+        }
+        included
+    }
+
+    /// Whether any file below the directory `dir` could be covered. Tree walks
+    /// skip directories where this is false.
+    pub fn covers_below(&self, dir: &Path) -> bool {
+        let Some(dir) = dir.to_str() else { return false };
+        let below = format!("{}/", dir);
+        self.patterns
+            .iter()
+            .filter(|p| !p.negated)
+            .any(|p| p.matches(&below) || glob_prefix_match(&p.glob, below.as_bytes()))
+    }
+}
+
+impl Pattern {
+    fn matches(&self, path: &str) -> bool {
+        // A directory pattern covers the files below it
+        let ancestors = path.match_indices('/').map(|(i, _)| &path[..i]);
+        if ancestors.into_iter().any(|dir| glob_match(&self.glob, dir.as_bytes())) {
+            return true;
+        }
+        !self.dir_only && glob_match(&self.glob, path.as_bytes())
+    }
+}
+
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern {
+        [] => text.is_empty(),
+        [b'*', b'*', rest @ ..] => match rest {
+            [] => true,
+            // `**/` matches zero or more whole directories
+            [b'/', rest @ ..] => {
+                glob_match(rest, text)
+                    || text
+                        .iter()
+                        .enumerate()
+                        .any(|(i, &c)| c == b'/' && glob_match(rest, &text[i + 1..]))
+            }
+            _ => glob_match(&pattern[1..], text),
+        },
+        [b'*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob_match(rest, tail)),
+        [b'[', rest @ ..] => match (class_match(rest, text.first().copied()), text) {
+            (Some((true, after)), [_, tail @ ..]) => glob_match(after, tail),
+            (Some(_), _) => false,
+            // Unterminated class: a literal '['
+            (None, [b'[', tail @ ..]) => glob_match(rest, tail),
+            (None, _) => false,
+        },
+        [b'\\', c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob_match(rest, tail)),
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob_match(rest, tail)),
+    }
+}
+
+// Whether `pattern` matches some path that starts with `text`, as glob_match
+// would; errs towards true
+fn glob_prefix_match(pattern: &[u8], text: &[u8]) -> bool {
+    if text.is_empty() {
+        return !pattern.is_empty();
+    }
+    match pattern {
+        [] => false,
+        [b'*', b'*', rest @ ..] => match rest {
+            [] => true,
+            [b'/', rest @ ..] => {
+                glob_prefix_match(rest, text)
+                    || text
+                        .iter()
+                        .enumerate()
+                        .any(|(i, &c)| c == b'/' && glob_prefix_match(rest, &text[i + 1..]))
+            }
+            _ => glob_prefix_match(&pattern[1..], text),
+        },
+        [b'*', rest @ ..] => {
+            for i in 0..=text.len() {
+                if glob_prefix_match(rest, &text[i..]) {
+                    return true;
+                }
+                if i < text.len() && text[i] == b'/' {
+                    break;
+                }
+            }
+            false
+        }
+        [b'?', rest @ ..] => matches!(text, [c, tail @ ..] if *c != b'/' && glob_prefix_match(rest, tail)),
+        [b'[', rest @ ..] => match (class_match(rest, text.first().copied()), text) {
+            (Some((true, after)), [_, tail @ ..]) => glob_prefix_match(after, tail),
+            (Some(_), _) => false,
+            (None, [b'[', tail @ ..]) => glob_prefix_match(rest, tail),
+            (None, _) => false,
+        },
+        [b'\\', c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob_prefix_match(rest, tail)),
+        [c, rest @ ..] => matches!(text, [t, tail @ ..] if t == c && glob_prefix_match(rest, tail)),
+    }
+}
+
+// `[...]` body after the '[': whether `c` is in the class, and the pattern after ']'
+fn class_match(class: &[u8], c: Option<u8>) -> Option<(bool, &[u8])> {
+    let (negated, mut body) = match class {
+        [b'!' | b'^', rest @ ..] => (true, rest),
+        _ => (false, class),
+    };
+    let mut found = false;
+    let mut first = true;
+    loop {
+        match body {
+            [] => return None,
+            [b']', rest @ ..] if !first => {
+                let hit = c.is_some_and(|c| c != b'/') && found != negated;
+                return Some((hit, rest));
+            }
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                found |= c.is_some_and(|c| (*lo..=*hi).contains(&c));
+                body = rest;
+            }
+            [x, rest @ ..] => {
+                found |= c == Some(*x);
+                body = rest;
+            }
+        }
+        first = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(text: &str) -> Profile {
+        Profile::parse(text)
+    }
+
+    #[test]
+    fn directory_globs_and_negation() {
+        let p = profile("src/core/**\n!**/*.png\n");
+        assert!(p.includes(Path::new("src/core/lib.rs")));
+        assert!(p.includes(Path::new("src/core/deep/mod.rs")));
+        assert!(!p.includes(Path::new("src/core/logo.png")));
+        assert!(!p.includes(Path::new("src/other/lib.rs")));
+    }
+
+    #[test]
+    fn unanchored_names_match_at_any_depth() {
+        let p = profile("# headers\n*.h\nMakefile\n");
+        assert!(p.includes(Path::new("a/b/c.h")));
+        assert!(p.includes(Path::new("c.h")));
+        assert!(p.includes(Path::new("tools/Makefile")));
+        assert!(!p.includes(Path::new("a/b/c.hpp")));
+    }
+
+    #[test]
+    fn anchored_and_directory_only_patterns() {
+        let p = profile("/docs/\n/README.md\n");
+        assert!(p.includes(Path::new("docs/guide/intro.md")));
+        assert!(!p.includes(Path::new("docs")));
+        assert!(p.includes(Path::new("README.md")));
+        assert!(!p.includes(Path::new("sub/README.md")));
+    }
+
+    #[test]
+    fn cone_style_profile() {
+        let p = profile("/*\n!/*/\n/lib/\n");
+        assert!(p.includes(Path::new("Cargo.toml")));
+        assert!(!p.includes(Path::new("tests/a.rs")));
+        assert!(p.includes(Path::new("lib/a.rs")));
+    }
+
+    #[test]
+    fn directories_that_cannot_hold_covered_files() {
+        let p = profile("src/core/**\n*.h\n!/vendor/\n");
+        assert!(p.covers_below(Path::new("src")));
+        assert!(p.covers_below(Path::new("src/core/deep")));
+        // `*.h` can match anywhere
+        assert!(p.covers_below(Path::new("docs")));
+
+        let p = profile("/src/core/\n/tools/*.py\n");
+        assert!(p.covers_below(Path::new("src")));
+        assert!(p.covers_below(Path::new("src/core/a")));
+        assert!(!p.covers_below(Path::new("src/other")));
+        assert!(p.covers_below(Path::new("tools")));
+        assert!(!p.covers_below(Path::new("tools/sub")));
+        assert!(!p.covers_below(Path::new("docs")));
+    }
+
+    #[test]
+    fn character_classes() {
+        assert!(glob_match(b"file[0-9].txt", b"file7.txt"));
+        assert!(!glob_match(b"file[!0-9].txt", b"file7.txt"));
+        assert!(glob_match(b"a?c", b"abc"));
+        assert!(!glob_match(b"a?c", b"a/c"));
+        assert!(!glob_match(b"a*c", b"ab/c"));
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::metrics::debug;
+
+// A pause this long ends one access sequence (roughly one build) and starts the next
+const SEQUENCE_GAP: Duration = Duration::from_secs(30);
+// How far ahead in a past sequence a file still counts as following another
+const WINDOW: usize = 8;
+// Files predicted per access
+const FANOUT: usize = 16;
+// Trace lines kept across mounts; older sequences are dropped at mount
+const MAX_TRACE_LINES: usize = 200_000;
+const FLUSH_EVERY: usize = 64;
+
+struct Recorder {
+    out: BufWriter<File>,
+    last_access: Option<Instant>,
+    // Files already recorded in the current sequence
+    seen: HashSet<PathBuf>,
+    unflushed: usize,
+}
+
+/// File access order, one path per line with a blank line between sequences,
+/// kept in a trace file across mounts. Paths are stored as raw bytes, so
+/// names that are not UTF-8 are traced too. Earlier sequences say which files tend
+/// to be opened after which, so they can be loaded before they are asked for.
+pub struct AccessTrace {
+    // Files seen within WINDOW after each file in earlier sequences, most frequent first
+    successors: HashMap<PathBuf, Vec<PathBuf>>,
+    recorder: Mutex<Recorder>,
+}
+
+impl AccessTrace {
+    pub fn open(trace_path: &Path) -> io::Result<Self> {
+        let mut lines: Vec<Vec<u8>> = match fs::read(trace_path) {
+            Ok(text) => text.split(|b| *b == b'\n').map(<[u8]>::to_vec).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        // The split leaves an empty piece after the final newline
+        if lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        if lines.len() > MAX_TRACE_LINES {
+            lines.drain(..lines.len() - MAX_TRACE_LINES);
+            let mut text = lines.join(&b'\n');
+            text.push(b'\n');
+            fs::write(trace_path, text)?;
+        }
+
+        let successors = learn(&lines);
+        debug!("[TRACE] loaded {} lines, {} files with successors", lines.len(), successors.len());
+
+        let file = OpenOptions::new().create(true).append(true).open(trace_path)?;
+        let mut out = BufWriter::new(file);
+        // Each mount starts a fresh sequence
+        if lines.last().is_some_and(|l| !l.is_empty()) {
+            writeln!(out)?;
+        }
+
+        Ok(Self {
+            successors,
+            recorder: Mutex::new(Recorder {
+                out,
+                last_access: None,
+                seen: HashSet::new(),
+                unflushed: 0,
+            }),
+        })
+    }
+
+    /// Record an open of `path` and return the files that usually follow it.
+    /// Only the first open of a file in a sequence is recorded or predicted from.
+    pub fn record(&self, path: &Path) -> Vec<PathBuf> {
+        let mut rec = self.recorder.lock().unwrap();
+        let now = Instant::now();
+        if rec.last_access.is_some_and(|t| now.duration_since(t) > SEQUENCE_GAP) {
+            let _ = writeln!(rec.out);
+            let _ = rec.out.flush();
+            rec.seen.clear();
+            rec.unflushed = 0;
+        }
+        rec.last_access = Some(now);
+
+        if !rec.seen.insert(path.to_path_buf()) {
+            return Vec::new();
+        }
+        let line = path.as_os_str().as_bytes();
+        if line.contains(&b'\n') {
+            debug!("[TRACE] not recording {:?}: name contains a newline", path);
+        } else {
+            let _ = rec.out.write_all(line);
+            let _ = rec.out.write_all(b"\n");
+            rec.unflushed += 1;
+            if rec.unflushed >= FLUSH_EVERY {
+                let _ = rec.out.flush();
+                rec.unflushed = 0;
+            }
+        }
+
+        self.successors
+            .get(path)
+            .map(|next| next.iter().filter(|p| !rec.seen.contains(*p)).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for AccessTrace {
+    fn drop(&mut self) {
+        if let Ok(rec) = self.recorder.get_mut() {
+            let _ = rec.out.flush();
+        }
+    }
+}
+
+fn learn(lines: &[Vec<u8>]) -> HashMap<PathBuf, Vec<PathBuf>> {
+    let mut counts: HashMap<&[u8], HashMap<&[u8], u32>> = HashMap::new();
+    for sequence in lines.split(|l| l.is_empty()) {
+        for (i, path) in sequence.iter().enumerate() {
+            let followers = counts.entry(path.as_slice()).or_default();
+            for next in sequence.iter().skip(i + 1).take(WINDOW) {
+                *followers.entry(next.as_slice()).or_default() += 1;
+            }
+        }
+    }
+
+    counts
+        .into_iter()
+        .filter(|(_, followers)| !followers.is_empty())
+        .map(|(path, followers)| {
+            let mut ranked: Vec<(&[u8], u32)> = followers.into_iter().collect();
+            ranked.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+            let next = ranked.into_iter().take(FANOUT).map(|(p, _)| path_of(p)).collect();
+            (path_of(path), next)
+        })
+        .collect()
+}
+
+fn path_of(line: &[u8]) -> PathBuf {
+    PathBuf::from(OsStr::from_bytes(line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &[&[u8]]) -> Vec<Vec<u8>> {
+        text.iter().map(|l| l.to_vec()).collect()
+    }
+
+    #[test]
+    fn learns_successors_within_the_window_ranked_by_count() {
+        let trace = lines(&[
+            b"main.c", b"util.h", b"util.c", b"",
+            b"main.c", b"util.c", b"",
+            b"caf\xe9.c", b"main.c",
+        ]);
+        let successors = learn(&trace);
+
+        assert_eq!(successors[Path::new("main.c")], vec![PathBuf::from("util.c"), PathBuf::from("util.h")]);
+        assert_eq!(successors[Path::new("util.h")], vec![PathBuf::from("util.c")]);
+        // Sequences do not run into each other, and last files predict nothing
+        assert!(!successors.contains_key(Path::new("util.c")));
+        assert_eq!(successors[&path_of(b"caf\xe9.c")], vec![PathBuf::from("main.c")]);
+
+        let long: Vec<Vec<u8>> = (0..WINDOW + 2).map(|i| format!("f{}", i).into_bytes()).collect();
+        let successors = learn(&long);
+        assert_eq!(successors[Path::new("f0")].len(), WINDOW);
+        assert!(!successors[Path::new("f0")].contains(&PathBuf::from(format!("f{}", WINDOW + 1))));
+    }
+}
@@ -0,0 +1,143 @@
+use dashmap::DashMap;
+use git2::{ObjectType, Oid, Repository};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+// Past these counts a table is dropped and refilled from the object database
+const MAX_TREES: usize = 100_000;
+const MAX_SIZES: usize = 1_000_000;
+
+#[derive(Clone)]
+pub struct TreeEntryMeta {
+    pub name: String,
+    pub oid: Oid,
+    pub mode: i32,
+    pub kind: ObjectType,
+}
+
+// One tree's entries, with their positions by name for path lookups
+struct Listing {
+    entries: Arc<Vec<TreeEntryMeta>>,
+    by_name: HashMap<String, usize>,
+}
+
+impl Listing {
+    fn find(&self, name: &str) -> Option<&TreeEntryMeta> {
+        self.by_name.get(name).map(|&i| &self.entries[i])
+    }
+}
+
+/// Tree listings and blob sizes by object id. Sizes come from object headers,
+/// so stat and readdir never inflate blob content. Everything is keyed by
+/// content, so nothing goes stale when HEAD moves.
+#[derive(Default)]
+pub struct TreeMeta {
+    trees: DashMap<Oid, Arc<Listing>>,
+    sizes: DashMap<Oid, u64>,
+}
+
+impl TreeMeta {
+    /// Entries of the tree `tree_oid`, in git order.
+    pub fn entries(&self, repo: &Repository, tree_oid: Oid) -> Option<Arc<Vec<TreeEntryMeta>>> {
+        self.listing(repo, tree_oid).map(|l| l.entries.clone())
+    }
+
+    fn listing(&self, repo: &Repository, tree_oid: Oid) -> Option<Arc<Listing>> {
+        if let Some(listing) = self.trees.get(&tree_oid) {
+            return Some(listing.clone());
+        }
+
+        let tree = repo.find_tree(tree_oid).ok()?;
+        let entries: Vec<TreeEntryMeta> = tree
+            .iter()
+            .filter_map(|e| {
+                Some(TreeEntryMeta {
+                    name: e.name()?.to_string(),
+                    oid: e.id(),
+                    mode: e.filemode(),
+                    kind: e.kind()?,
+                })
+            })
+            .collect();
+        let by_name = entries.iter().enumerate().map(|(i, e)| (e.name.clone(), i)).collect();
+        let listing = Arc::new(Listing { entries: Arc::new(entries), by_name });
+        if self.trees.len() >= MAX_TREES {
+            self.trees.clear();
+        }
+        self.trees.insert(tree_oid, listing.clone());
+        Some(listing)
+    }
+
+    pub fn blob_size(&self, repo: &Repository, oid: Oid) -> Option<u64> {
+        if let Some(size) = self.sizes.get(&oid) {
+            return Some(*size);
+        }
+
+        let (size, _) = repo.odb().ok()?.read_header(oid).ok()?;
+        if self.sizes.len() >= MAX_SIZES {
+            self.sizes.clear();
+        }
+        self.sizes.insert(oid, size as u64);
+        Some(size as u64)
+    }
+
+    /// Id of the tree at directory `path` in commit `head`.
+    pub fn tree_at(&self, repo: &Repository, head: Oid, path: &Path) -> Option<Oid> {
+        let mut oid = repo.find_commit(head).ok()?.tree_id();
+        for comp in path.iter() {
+            let name = comp.to_str()?;
+            let listing = self.listing(repo, oid)?;
+            let entry = listing.find(name)?;
+            if entry.kind != ObjectType::Tree {
+                return None;
+            }
+            oid = entry.oid;
+        }
+        Some(oid)
+    }
+
+    /// Tree entry for `path` in commit `head`; None for the root.
+    pub fn entry_at(&self, repo: &Repository, head: Oid, path: &Path) -> Option<TreeEntryMeta> {
+        let parent = self.tree_at(repo, head, path.parent()?)?;
+        let name = path.file_name()?.to_str()?;
+        self.listing(repo, parent)?.find(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{FileMode, Signature};
+
+    #[test]
+    fn resolves_paths_and_sizes_from_tree_metadata() {
+        let dir = std::env::temp_dir().join(format!("gitfs-tree-meta-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let blob = repo.blob(b"hello world").unwrap();
+        let head = {
+            let mut sub = repo.treebuilder(None).unwrap();
+            sub.insert("b.txt", repo.blob(b"b").unwrap(), FileMode::Blob.into()).unwrap();
+            let sub = sub.write().unwrap();
+            let mut root = repo.treebuilder(None).unwrap();
+            root.insert("a.txt", blob, FileMode::Blob.into()).unwrap();
+            root.insert("sub", sub, FileMode::Tree.into()).unwrap();
+            let tree = repo.find_tree(root.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+        };
+
+        let meta = TreeMeta::default();
+        let entry = meta.entry_at(&repo, head, Path::new("a.txt")).unwrap();
+        assert_eq!((entry.oid, entry.kind), (blob, ObjectType::Blob));
+        assert_eq!(meta.blob_size(&repo, entry.oid), Some(11));
+        assert_eq!(meta.entry_at(&repo, head, Path::new("sub/b.txt")).unwrap().kind, ObjectType::Blob);
+        assert!(meta.tree_at(&repo, head, Path::new("sub")).is_some());
+
+        assert!(meta.entry_at(&repo, head, Path::new("a.txt/x")).is_none());
+        assert!(meta.entry_at(&repo, head, Path::new("missing")).is_none());
+        assert!(meta.tree_at(&repo, head, Path::new("a.txt")).is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -1,9 +1,14 @@
 use fuser::FileType;
 use git2::FileMode;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 pub const ROOT_INO: u64 = 1;
 
+// Reported by readdir for entries the kernel has not looked up yet; the real
+// inode is only assigned on lookup (same convention as libfuse).
+pub const UNKNOWN_INO: u64 = 0xffff_ffff;
+
 #[derive(Clone)]
 pub struct Node {
     pub ino: u64,
@@ -11,6 +16,22 @@ pub struct Node {
     pub size: u64,
     pub path: PathBuf,
     pub git_mode: Option<FileMode>,
+    // Commit time for git content; overlay entries carry their own times
+    pub mtime: SystemTime,
+    // Number of lookups the kernel holds on this inode; freed on forget when it drops to zero
+    pub nlookup: u64,
+}
+
+/// Kind of file `mknod` creates for `mode`; None for types it cannot create.
+pub fn mknod_kind(mode: u32) -> Option<FileType> {
+    match mode & libc::S_IFMT {
+        libc::S_IFREG => Some(FileType::RegularFile),
+        libc::S_IFIFO => Some(FileType::NamedPipe),
+        libc::S_IFSOCK => Some(FileType::Socket),
+        libc::S_IFCHR => Some(FileType::CharDevice),
+        libc::S_IFBLK => Some(FileType::BlockDevice),
+        _ => None,
+    }
 }
 
 pub fn i32_to_filemode(mode: i32) -> FileMode {
@@ -32,3 +53,11 @@ pub fn git_mode_to_perm(mode: FileMode) -> u16 {
         _ => 0o644,
     }
 }
+
+pub fn perm_to_git_mode(perm: u16) -> FileMode {
+    if perm & 0o100 != 0 {
+        FileMode::BlobExecutable
+    } else {
+        FileMode::Blob
+    }
+}
@@ -0,0 +1,261 @@
+use fuser::{FileType, ReplyEmpty, ReplyXattr};
+use git2::{FileMode, ObjectType, Oid, Repository};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use crate::metrics::debug;
+use crate::types::Node;
+use crate::overlay_store::{OverlayEntry, OverlayStore};
+
+// Read-only attributes computed from git; everything else under `user.` is stored in the overlay
+const GIT_XATTR_PREFIX: &str = "user.git.";
+const GIT_XATTRS: [&str; 4] = ["user.git.oid", "user.git.mode", "user.git.status", "user.git.commit"];
+
+pub fn get_xattr(
+    node: &Node,
+    name: &OsStr,
+    size: u32,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: Oid,
+    reply: ReplyXattr,
+) {
+    debug!("[GETXATTR] path={:?}, name={:?}", node.path, name);
+    let entry = overlay_store.get(&node.path);
+
+    let value = match name.to_str() {
+        Some(n) if n.starts_with(GIT_XATTR_PREFIX) => {
+            git_xattr(n, node, entry.as_ref(), overlay_store, repo, head)
+        }
+        _ => entry.and_then(|e| e.xattrs.get(name).cloned()),
+    };
+
+    match value {
+        Some(v) => reply_value(&v, size, reply),
+        None => reply.error(libc::ENODATA),
+    }
+}
+
+pub fn list_xattr(
+    node: &Node,
+    size: u32,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: Oid,
+    reply: ReplyXattr,
+) {
+    let entry = overlay_store.get(&node.path);
+    let mut names = Vec::new();
+
+    for name in git_xattr_names(node, entry.as_ref(), repo, head) {
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+    }
+    if let Some(entry) = &entry {
+        for name in entry.xattrs.keys() {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+    }
+
+    reply_value(&names, size, reply);
+}
+
+pub fn set_xattr(
+    node: &Node,
+    name: &OsStr,
+    value: &[u8],
+    flags: i32,
+    overlay_store: &OverlayStore,
+    reply: ReplyEmpty,
+) {
+    debug!("[SETXATTR] path={:?}, name={:?}, len={}", node.path, name, value.len());
+    if let Err(err) = check_writable(name) {
+        return reply.error(err);
+    }
+
+    let exists = overlay_store
+        .get(&node.path)
+        .is_some_and(|e| e.xattrs.contains_key(name));
+    if flags & libc::XATTR_CREATE != 0 && exists {
+        return reply.error(libc::EEXIST);
+    }
+    if flags & libc::XATTR_REPLACE != 0 && !exists {
+        return reply.error(libc::ENODATA);
+    }
+
+    overlay_store.update(&node.path, node.kind, |entry| {
+        entry.xattrs.insert(name.to_os_string(), value.to_vec());
+    });
+    reply.ok();
+}
+
+pub fn remove_xattr(node: &Node, name: &OsStr, overlay_store: &OverlayStore, reply: ReplyEmpty) {
+    debug!("[REMOVEXATTR] path={:?}, name={:?}", node.path, name);
+    if let Err(err) = check_writable(name) {
+        return reply.error(err);
+    }
+
+    let exists = overlay_store
+        .get(&node.path)
+        .is_some_and(|e| e.xattrs.contains_key(name));
+    if !exists {
+        return reply.error(libc::ENODATA);
+    }
+
+    overlay_store.update(&node.path, node.kind, |entry| {
+        entry.xattrs.remove(name);
+    });
+    reply.ok();
+}
+
+fn check_writable(name: &OsStr) -> Result<(), libc::c_int> {
+    match name.to_str() {
+        Some(n) if n.starts_with(GIT_XATTR_PREFIX) => Err(libc::EPERM),
+        Some(n) if n.starts_with("user.") => Ok(()),
+        _ => Err(libc::ENOTSUP),
+    }
+}
+
+fn git_xattr(
+    name: &str,
+    node: &Node,
+    entry: Option<&OverlayEntry>,
+    overlay_store: &OverlayStore,
+    repo: &Repository,
+    head: Oid,
+) -> Option<Vec<u8>> {
+    if entry.is_some_and(|e| !e.committable()) {
+        return match name {
+            "user.git.commit" => Some(head.to_string().into_bytes()),
+            "user.git.status" => Some(b"untracked".to_vec()),
+            _ => None,
+        };
+    }
+
+    let tracked = git_entry(repo, head, &node.path);
+    let modified = entry.is_some_and(|e| e.modified);
+    // Only the executable bit of a regular file reaches git
+    let mode_changed = match (entry.and_then(|e| e.git_mode()), &tracked) {
+        (Some(mode), Some((_, head_mode))) => u32::from(mode) != *head_mode as u32,
+        _ => false,
+    };
+
+    let value = match name {
+        "user.git.commit" => head.to_string(),
+        "user.git.status" => match (&tracked, modified || mode_changed) {
+            (None, _) => "added",
+            (Some(_), true) => "modified",
+            (Some(_), false) => "clean",
+        }
+        .to_string(),
+        "user.git.mode" => {
+            let mode = match (entry.and_then(|e| e.git_mode()), &tracked) {
+                (Some(mode), _) => u32::from(mode),
+                (None, Some((_, filemode))) => *filemode as u32,
+                (None, None) if node.kind == FileType::Directory => u32::from(FileMode::Tree),
+                (None, None) => u32::from(node.git_mode.unwrap_or(FileMode::Blob)),
+            };
+            format!("{:06o}", mode)
+        }
+        "user.git.oid" => match (entry, tracked) {
+            (_, Some((oid, _))) if !modified || node.kind != FileType::RegularFile => oid.to_string(),
+            // Shares a git blob without a copy of its own
+            (Some(OverlayEntry { data_id: None, base_oid: Some(oid), .. }), _) => oid.to_string(),
+            // Hash what the overlay holds so the oid matches what a commit would record
+            _ if node.kind == FileType::RegularFile => {
+                let data = fs::read(overlay_store.data_path_of(&node.path)?).ok()?;
+                Oid::hash_object(ObjectType::Blob, &data).ok()?.to_string()
+            }
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(value.into_bytes())
+}
+
+// The names `git_xattr` has a value for, without computing the values
+fn git_xattr_names(node: &Node, entry: Option<&OverlayEntry>, repo: &Repository, head: Oid) -> Vec<&'static str> {
+    if entry.is_some_and(|e| !e.committable()) {
+        return vec!["user.git.status", "user.git.commit"];
+    }
+    // Regular files always have an oid, hashed from the overlay if need be
+    let has_oid = node.kind == FileType::RegularFile || git_entry(repo, head, &node.path).is_some();
+    GIT_XATTRS
+        .into_iter()
+        .filter(|name| *name != "user.git.oid" || has_oid)
+        .collect()
+}
+
+fn git_entry(repo: &Repository, head: Oid, path: &Path) -> Option<(Oid, i32)> {
+    let tree = repo.find_commit(head).ok()?.tree().ok()?;
+    if path.as_os_str().is_empty() {
+        return Some((tree.id(), i32::from(FileMode::Tree)));
+    }
+    let entry = tree.get_path(path).ok()?;
+    Some((entry.id(), entry.filemode()))
+}
+
+fn reply_value(value: &[u8], size: u32, reply: ReplyXattr) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if (size as usize) < value.len() {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay_store::AttrChange;
+    use git2::Signature;
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    #[test]
+    fn status_follows_the_mode_back_to_clean() {
+        let dir = std::env::temp_dir().join(format!("gitfs-xattr-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        let blob = repo.blob(b"#!/bin/sh\n").unwrap();
+        let head = {
+            let mut builder = repo.treebuilder(None).unwrap();
+            builder.insert("run.sh", blob, FileMode::Blob.into()).unwrap();
+            let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+            let sig = Signature::now("test", "test@example.com").unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "init", &tree, &[]).unwrap()
+        };
+        let store = OverlayStore::new(dir.join("overlay")).unwrap();
+        let path = PathBuf::from("run.sh");
+        let node = Node {
+            ino: 2,
+            kind: FileType::RegularFile,
+            size: 10,
+            path: path.clone(),
+            git_mode: Some(FileMode::Blob),
+            mtime: SystemTime::now(),
+            nlookup: 1,
+        };
+        let xattr = |name| {
+            let entry = store.get(&path);
+            String::from_utf8(git_xattr(name, &node, entry.as_ref(), &store, &repo, head).unwrap()).unwrap()
+        };
+        let chmod = |perm| store.set_attrs(&path, FileType::RegularFile, &AttrChange { perm: Some(perm), ..Default::default() });
+
+        chmod(0o755);
+        assert_eq!(xattr("user.git.status"), "modified");
+        assert_eq!(xattr("user.git.mode"), "100755");
+        // Content is still the blob in HEAD
+        assert_eq!(xattr("user.git.oid"), blob.to_string());
+
+        chmod(0o600);
+        assert_eq!(xattr("user.git.status"), "clean");
+        store.touch(&path);
+        assert_eq!(xattr("user.git.status"), "modified");
+        let _ = fs::remove_dir_all(&dir);
+    }
+}